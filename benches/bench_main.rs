@@ -2,6 +2,10 @@ use criterion::criterion_main;
 
 mod benchmarks;
 
+#[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
 criterion_main! {
   benchmarks::static_search_tree::benches,
 }
@@ -0,0 +1,153 @@
+use alloc::alloc::{alloc, dealloc, handle_alloc_error, GlobalAlloc, Layout};
+use alloc::sync::Arc;
+use core::mem::{self, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::slice;
+
+use super::error::Error;
+
+/// Memory for the cells of a map and the nodes of its index.
+///
+/// # Safety
+///
+/// `allocate` must return null or memory fitting `layout` that stays valid
+/// until it's handed back to `deallocate` with the same layout.
+pub unsafe trait Allocator: Send + Sync + 'static {
+    fn allocate(&self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    ///
+    /// `ptr` must have come from `allocate` on this allocator with `layout`.
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout);
+}
+
+// Anything that could be installed as the global allocator will do, which
+// lets a map keep its cells in another allocator without the whole process
+// switching over
+unsafe impl<A: GlobalAlloc + Send + Sync + 'static> Allocator for A {
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.alloc(layout) }
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc(ptr, layout)
+    }
+}
+
+// Whichever allocator is installed as `#[global_allocator]`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Global;
+
+unsafe impl GlobalAlloc for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        dealloc(ptr, layout)
+    }
+}
+
+pub fn global() -> Arc<dyn Allocator> {
+    Arc::new(Global)
+}
+
+// A slice that never moves, in memory from an Allocator it goes back to once dropped
+pub struct Allocation<T> {
+    ptr: NonNull<T>,
+    len: usize,
+    allocator: Arc<dyn Allocator>,
+}
+
+unsafe impl<T: Send> Send for Allocation<T> {}
+unsafe impl<T: Sync> Sync for Allocation<T> {}
+
+impl<T> Allocation<T> {
+    pub fn uninit(len: usize, allocator: Arc<dyn Allocator>) -> Allocation<MaybeUninit<T>> {
+        let layout = Layout::array::<T>(len).expect("allocation too large");
+        Self::try_uninit(len, allocator).unwrap_or_else(|_| handle_alloc_error(layout))
+    }
+
+    // Hands back an error where `uninit` would abort the process
+    pub fn try_uninit(
+        len: usize,
+        allocator: Arc<dyn Allocator>,
+    ) -> Result<Allocation<MaybeUninit<T>>, Error> {
+        let layout = Layout::array::<T>(len).map_err(|_| Error::AllocationFailed)?;
+        // zero-sized allocations are never handed to the allocator
+        let ptr = if layout.size() == 0 {
+            NonNull::dangling()
+        } else {
+            NonNull::new(allocator.allocate(layout))
+                .ok_or(Error::AllocationFailed)?
+                .cast()
+        };
+
+        Ok(Allocation {
+            ptr,
+            len,
+            allocator,
+        })
+    }
+
+    pub fn from_fn<F: FnMut() -> T>(len: usize, allocator: Arc<dyn Allocator>, f: F) -> Self {
+        Self::fill(Self::uninit(len, allocator), f)
+    }
+
+    pub fn try_from_fn<F: FnMut() -> T>(
+        len: usize,
+        allocator: Arc<dyn Allocator>,
+        f: F,
+    ) -> Result<Self, Error> {
+        Ok(Self::fill(Self::try_uninit(len, allocator)?, f))
+    }
+
+    fn fill<F: FnMut() -> T>(mut memory: Allocation<MaybeUninit<T>>, mut f: F) -> Self {
+        for slot in memory.iter_mut() {
+            slot.write(f());
+        }
+        unsafe { memory.assume_init() }
+    }
+
+    pub fn allocator(&self) -> &Arc<dyn Allocator> {
+        &self.allocator
+    }
+}
+
+impl<T> Allocation<MaybeUninit<T>> {
+    // Safety: every element must have been written
+    pub unsafe fn assume_init(self) -> Allocation<T> {
+        let this = mem::ManuallyDrop::new(self);
+        Allocation {
+            ptr: this.ptr.cast(),
+            len: this.len,
+            allocator: ptr::read(&this.allocator),
+        }
+    }
+}
+
+impl<T> Deref for Allocation<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for Allocation<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> Drop for Allocation<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(&mut **self as *mut [T]) };
+
+        let layout = Layout::array::<T>(self.len).unwrap();
+        if layout.size() != 0 {
+            unsafe { self.allocator.deallocate(self.ptr.as_ptr().cast(), layout) };
+        }
+    }
+}
@@ -1,209 +1,1217 @@
-use std::borrow::Borrow;
-use std::cell::UnsafeCell;
-use std::collections::VecDeque;
-use std::convert::TryInto;
-use std::fmt::{self, Debug};
-use std::mem::MaybeUninit;
-use std::ptr::NonNull;
-use std::sync::atomic::{AtomicBool, Ordering};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::cell::UnsafeCell;
+use core::cmp::Ordering;
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::hash::{Hash, Hasher};
+use core::iter::{self, FromIterator};
+use core::marker::PhantomData;
+use core::mem::{self, MaybeUninit};
+use core::ops::{Bound, Index, Range, RangeBounds};
+use core::ptr::NonNull;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
+#[cfg(feature = "std")]
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "std")]
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, RwLock, Weak};
+#[cfg(feature = "std")]
 use std::thread;
+#[cfg(feature = "std")]
 use std::time;
 
 use num_rational::{Ratio, Rational};
 
-use super::cell::{Cell, CellGuard, CellIterator, Key, Marker};
+use super::allocator::{self, Allocation, Allocator, Global};
+use super::cell::{back_off, Cell, CellGuard, CellIterator, Key, Marker, MAX_RETRIES};
+use super::comparator::{Comparator, OrdComparator};
+use super::error::Error;
+#[cfg(feature = "std")]
+use super::format::{self, Encode};
+#[cfg(feature = "mmap")]
+use super::mapped::{self, Pod};
+use super::monoid::Monoid;
 use super::packed_memory_array::PackedMemoryArray;
-
+#[cfg(feature = "std")]
+use super::packed_memory_array::Storage;
+use super::snapshot::{Snapshot, SnapshotState};
+use super::sync::{Mutex, RwLock};
+use super::transaction::{Transaction, MAX_TRANSACTION_ATTEMPTS};
+#[cfg(feature = "std")]
+use super::wal::Wal;
+
+#[cfg(feature = "std")]
 const INDEX_UPDATE_DELAY: time::Duration = time::Duration::from_millis(50);
+// Smaller arrays are too short to hold a single index block
+pub(super) const MIN_CAPACITY: u32 = 2;
+// The most keys the smallest array past MIN_CAPACITY is sized for
+const DEFAULT_CAPACITY: u32 = 32;
+
+// Arrays waiting on the indexing thread
+#[cfg(feature = "std")]
+type IndexRequests<K, V> = Sender<Weak<PackedMemoryArray<Cell<K, V>>>>;
+
+// A write as the log of a durable map records it
+pub enum Record<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
 
-pub struct BTreeMap<K: Clone + Ord, V: Clone> {
+pub struct BTreeMap<K, V, M: Monoid<K, V> = (), C: Comparator<K> = OrdComparator> {
     data: Arc<PackedMemoryArray<Cell<K, V>>>,
-    index: Arc<RwLock<BlockIndex<K, V>>>,
-    tx: Sender<Weak<PackedMemoryArray<Cell<K, V>>>>,
+    len: AtomicUsize,
+    // shared with every index built for the map
+    comparator: Arc<C>,
+    index: Arc<RwLock<BlockIndex<K, V, M, C>>>,
+    #[cfg(feature = "std")]
+    tx: Option<IndexRequests<K, V>>,
+    #[cfg(feature = "std")]
     index_updating: Arc<AtomicBool>,
+    index_generation: Arc<AtomicUsize>,
+    #[cfg(feature = "std")]
+    indexer: Option<thread::JoinHandle<()>>,
+    snapshots: Mutex<Vec<Weak<SnapshotState<K, V>>>>,
+    // how many snapshots are alive, so writes skip the lock while there are none
+    live_snapshots: Arc<AtomicUsize>,
+    // set for maps opened with `open`
+    #[cfg(feature = "std")]
+    wal: Option<Mutex<Wal<K, V>>>,
 }
 
 impl<K, V> BTreeMap<K, V>
 where
-    K: 'static + Clone + Ord,
-    V: 'static + Clone,
+    K: 'static + Ord,
+    V: 'static,
 {
     pub fn new(capacity: u32) -> BTreeMap<K, V> {
-        let packed_cells = PackedMemoryArray::with_capacity(capacity);
+        Self::with_monoid(capacity)
+    }
+
+    // Like `new`, but an error rather than an abort when there's no memory for it
+    pub fn try_with_capacity(capacity: u32) -> Result<BTreeMap<K, V>, Error> {
+        let capacity = capacity.max(MIN_CAPACITY);
+        let packed_cells = PackedMemoryArray::try_with_capacity(capacity, allocator::global())?;
+        let nodes = BlockSearchTree::try_allocate(packed_cells.len(), packed_cells.allocator())?;
+        Self::from_cells_and_nodes(packed_cells, nodes, Arc::new(OrdComparator))
+    }
+}
+
+impl<K, V, M, C> BTreeMap<K, V, M, C>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    // Keeps an `M` aggregate of every subtree of the index for `aggregate`
+    pub fn with_monoid(capacity: u32) -> BTreeMap<K, V, M, C>
+    where
+        C: Default,
+    {
+        Self::with_comparator(capacity, C::default())
+    }
+
+    // Orders keys with `comparator` rather than their `Ord` implementation
+    pub fn with_comparator(capacity: u32, comparator: C) -> BTreeMap<K, V, M, C> {
+        Self::with_comparator_and_allocator(capacity, comparator, Global)
+    }
+
+    // Takes the memory for its cells and index from `allocator` instead of
+    // the global allocator, including whenever the map grows
+    pub fn with_allocator<A: Allocator>(capacity: u32, allocator: A) -> BTreeMap<K, V, M, C>
+    where
+        C: Default,
+    {
+        Self::with_comparator_and_allocator(capacity, C::default(), allocator)
+    }
+
+    pub fn with_comparator_and_allocator<A: Allocator>(
+        capacity: u32,
+        comparator: C,
+        allocator: A,
+    ) -> BTreeMap<K, V, M, C> {
+        Self::from_packed_cells(
+            PackedMemoryArray::with_capacity(capacity.max(MIN_CAPACITY), Arc::new(allocator)),
+            Arc::new(comparator),
+        )
+    }
+
+    // Lays out already sorted, deduplicated entries without going through `insert`
+    fn from_sorted_entries(
+        entries: Vec<(K, V)>,
+        capacity: u32,
+        comparator: Arc<C>,
+        allocator: Arc<dyn Allocator>,
+    ) -> BTreeMap<K, V, M, C> {
+        Self::from_packed_cells(Self::pack(entries, capacity, allocator), comparator)
+    }
+
+    fn from_packed_cells(
+        packed_cells: PackedMemoryArray<Cell<K, V>>,
+        comparator: Arc<C>,
+    ) -> BTreeMap<K, V, M, C> {
+        let nodes = BlockSearchTree::allocate(packed_cells.len(), packed_cells.allocator());
+        Self::from_cells_and_nodes(packed_cells, nodes, comparator).unwrap()
+    }
+
+    fn from_cells_and_nodes(
+        packed_cells: PackedMemoryArray<Cell<K, V>>,
+        nodes: NodeMemory<K, V, M>,
+        comparator: Arc<C>,
+    ) -> Result<BTreeMap<K, V, M, C>, Error> {
         let data = Arc::new(packed_cells);
+        let len = data
+            .as_slice()
+            .iter()
+            .filter(|cell| cell.is_filled())
+            .count();
 
-        let raw_index = Self::generate_index(Arc::clone(&data));
+        let raw_index = Self::generate_index_in(Arc::clone(&data), Arc::clone(&comparator), nodes);
         let index = Arc::new(RwLock::new(raw_index));
+        let index_generation = Arc::new(AtomicUsize::new(0));
+
+        #[cfg(feature = "std")]
+        let (tx, index_updating, indexer) = {
+            let thread_index = Arc::clone(&index);
+            let (tx, rx) = channel::<Weak<PackedMemoryArray<Cell<K, V>>>>();
+            let (index_updating, indexer) =
+                Self::start_indexing_thread(thread_index, Arc::clone(&index_generation), rx)?;
+            (tx, index_updating, indexer)
+        };
 
-        let thread_index = Arc::clone(&index);
-        let (tx, rx) = channel::<Weak<PackedMemoryArray<Cell<K, V>>>>();
-        let index_updating = Self::start_indexing_thread(thread_index, rx);
-
-        BTreeMap {
+        Ok(BTreeMap {
             index,
             data,
-            tx,
+            len: AtomicUsize::new(len),
+            comparator,
+            #[cfg(feature = "std")]
+            tx: Some(tx),
+            #[cfg(feature = "std")]
             index_updating,
-        }
+            index_generation,
+            #[cfg(feature = "std")]
+            indexer: Some(indexer),
+            snapshots: Mutex::new(Vec::new()),
+            live_snapshots: Arc::new(AtomicUsize::new(0)),
+            #[cfg(feature = "std")]
+            wal: None,
+        })
     }
 
-    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
     where
-        Q: Ord,
+        C: Comparator<Q>,
         K: Borrow<Q>,
     {
-        self.index.read().unwrap().get(key)
+        self.try_get(key).unwrap()
     }
 
-    pub fn insert<'a>(&'a mut self, key: K, value: V)
+    pub fn try_get<Q: ?Sized>(&self, key: &Q) -> Result<Option<&V>, Error>
     where
-        K: Debug,
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.index.read().map_err(|_| Error::Poisoned)?.get(key)
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(self.data.active_range.start, self.end_ptr())
+    }
+
+    pub fn range<Q: ?Sized, R>(&self, range: R) -> Iter<'_, K, V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
+    {
+        let (start, end) = self.cells_in_range(range);
+        Iter::new(start, end)
+    }
+
+    // Values are modified where they sit, no markers are taken and no cells
+    // move. Aggregates recount the blocks they were lent from.
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let cell_guard = self.find_cell(key)?;
+        self.preserve_for_snapshots(cell_guard.inner);
+        self.data.mark_lent(cell_guard.inner);
+        unsafe { cell_guard.inner.entry_mut() }.map(|(_, value)| value)
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        self.range_mut::<K, _>(..)
+    }
+
+    pub fn range_mut<Q: ?Sized, R>(&mut self, range: R) -> IterMut<'_, K, V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
+    {
+        let (start, end) = self.cells_in_range(range);
+
+        let mut address = start;
+        while address < end {
+            self.preserve_for_snapshots(address);
+            if unsafe { (*address).is_filled() } {
+                self.data.mark_lent(address);
+            }
+            address = unsafe { address.add(1) };
+        }
+
+        IterMut::new(start, end)
+    }
+
+    fn cells_in_range<Q: ?Sized, R>(&self, range: R) -> (*const Cell<K, V>, *const Cell<K, V>)
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
     {
         let index = self.index.read().unwrap();
-        let (block, min_key) = match index.get_block_for_insert(&key) {
-            SearchResult::Block(block, min_key) => (block, min_key),
-            _ => panic!("No block found for insert of key {:?}", key),
+        let start = match range.start_bound() {
+            Bound::Included(key) => index.position_after(key, false),
+            Bound::Excluded(key) => index.position_after(key, true),
+            Bound::Unbounded => self.data.active_range.start,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => index.position_after(key, true),
+            Bound::Excluded(key) => index.position_after(key, false),
+            Bound::Unbounded => self.end_ptr(),
         };
 
-        // Todo: Clean up (abstract out CellGuard)
-        let iter = self
-            .data
-            .into_iter()
-            .skip_while(|&x| x as *const _ != block.cell_slice_ptr)
-            .map(|c| unsafe { CellGuard::from_raw(c).unwrap() });
+        (start, end.max(start))
+    }
 
-        let mut selected_cell: Option<CellGuard<K, V>> = None;
-        for mut cell_guard in iter {
-            if cell_guard.is_empty() {
-                // node says there's a cell smaller than ours, keep looking
-                if selected_cell.is_none() && min_key <= Key::Value(&key) {
-                    continue;
-                // block is empty
-                } else {
-                    selected_cell = None;
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.try_retain(f).unwrap();
+    }
+
+    // Like `retain`, but an error rather than a panic when a durable map can't
+    // log a removal. The entries removed before it stay removed.
+    pub fn try_retain<F>(&mut self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        let mut extract = self.extract_if(|key, value| !f(key, value));
+        extract.by_ref().for_each(drop);
+        extract.failed.map_or(Ok(()), Err)
+    }
+
+    // Removes and yields every entry the predicate accepts. Density is
+    // restored and the index rebuilt once the iterator is dropped. It stops
+    // early at an entry a durable map can't log the removal of, which
+    // `try_retain` reports.
+    pub fn extract_if<F>(&mut self, predicate: F) -> ExtractIf<'_, K, V, M, C, F>
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        let address = self.data.active_range.start;
+        ExtractIf {
+            map: self,
+            address,
+            removed: 0,
+            failed: None,
+            predicate,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.try_clear().unwrap();
+    }
+
+    pub fn try_clear(&mut self) -> Result<(), Error> {
+        self.try_retain(|_, _| false)
+    }
+
+    // Moves every entry from `key` onwards into a new map
+    pub fn split_off<Q: ?Sized>(&mut self, key: &Q) -> BTreeMap<K, V, M, C>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.try_split_off(key).unwrap()
+    }
+
+    // Like `split_off`, but an error rather than a panic when a durable map
+    // can't log the removals, in which case nothing moves
+    pub fn try_split_off<Q: ?Sized>(&mut self, key: &Q) -> Result<BTreeMap<K, V, M, C>, Error>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let split_at = self.index.read().unwrap().position_after(key, false);
+        self.log_removes(Iter::new(split_at, self.end_ptr()))?;
+        let upper = self.take_entries(split_at);
+        let lower = self.take_entries(self.data.active_range.start);
+
+        self.replace_cells(lower);
+        // both halves keep the room the whole map had
+        Ok(Self::from_sorted_entries(
+            upper,
+            self.data.requested_capacity,
+            Arc::clone(&self.comparator),
+            self.data.allocator(),
+        ))
+    }
+
+    // Moves every entry of `other` into this map, replacing entries with equal keys
+    pub fn append(&mut self, other: &mut BTreeMap<K, V, M, C>) {
+        self.try_append(other).unwrap();
+    }
+
+    // Like `append`, but an error rather than a panic when a durable map can't
+    // log the move. Nothing moves if this map's log refuses the entries. Once
+    // it has them they move regardless, and a refusal from `other`'s log to
+    // record their removal is what's returned.
+    pub fn try_append(&mut self, other: &mut BTreeMap<K, V, M, C>) -> Result<(), Error> {
+        self.log_inserts(other.iter())?;
+        let removed = other.log_removes(other.iter());
+
+        let theirs = other.take_entries(other.data.active_range.start);
+        other.redistribute();
+        self.merge_entries(theirs);
+        removed
+    }
+
+    // Writes every cell, gaps included, so `load_from` restores this exact layout
+    #[cfg(feature = "std")]
+    pub fn save_to<W: Write>(&self, writer: W) -> io::Result<()>
+    where
+        K: Encode,
+        V: Encode,
+    {
+        format::write_cells(&self.data, writer)
+    }
+
+    // Only the index is rebuilt, cells stay exactly where they were saved
+    #[cfg(feature = "std")]
+    pub fn load_from<R: Read>(reader: R) -> io::Result<BTreeMap<K, V, M, C>>
+    where
+        K: Encode,
+        V: Encode,
+        C: Default,
+    {
+        let cells = format::read_cells(reader)?;
+        Ok(Self::from_packed_cells(cells, Arc::new(C::default())))
+    }
+
+    // Keeps the cells in the file at `path`, creating it with room for
+    // `capacity` keys if it doesn't exist yet. The map must be dropped before
+    // the file is opened again, and so must any snapshot taken from it.
+    #[cfg(feature = "mmap")]
+    pub fn mapped<P: AsRef<Path>>(path: P, capacity: u32) -> io::Result<BTreeMap<K, V, M, C>>
+    where
+        K: Pod,
+        V: Pod,
+        C: Default,
+    {
+        let cells = mapped::open_cells(path.as_ref(), capacity)?;
+        Ok(Self::from_packed_cells(cells, Arc::new(C::default())))
+    }
+
+    // Keeps the map durable in the directory at `path`, replaying whatever
+    // was logged since the last checkpoint. Every write is logged before it
+    // lands, except values changed in place through `get_mut`, `iter_mut` or
+    // `range_mut`, which are only saved by the next checkpoint.
+    #[cfg(feature = "std")]
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<BTreeMap<K, V, M, C>>
+    where
+        K: Encode,
+        V: Encode,
+        C: Default,
+    {
+        let (wal, checkpoint, records) = Wal::recover(path.as_ref())?;
+        let mut map = match checkpoint {
+            Some(cells) => Self::from_packed_cells(cells, Arc::new(C::default())),
+            // room for every logged key, the map grows past it like any other
+            None => {
+                let capacity = records.len().min(u32::MAX as usize) as u32;
+                Self::with_monoid(capacity.max(DEFAULT_CAPACITY))
+            }
+        };
+
+        // the log isn't attached yet, so replaying writes nothing back to it
+        for record in records {
+            match record {
+                Record::Insert(key, value) => map.try_insert(key, value)?,
+                Record::Remove(key) => {
+                    map.try_remove(&key)?;
                 }
+            }
+        }
+
+        map.wal = Some(Mutex::new(wal));
+        Ok(map)
+    }
+
+    // Saves the cells of a map opened with `open` and empties its log
+    #[cfg(feature = "std")]
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        match &self.wal {
+            Some(wal) => wal.lock().unwrap().checkpoint(&self.data),
+            None => Ok(()),
+        }
+    }
+
+    // Writes dirty pages of a file-backed map, or the log of a durable one, out to disk
+    #[cfg(feature = "std")]
+    pub fn flush(&self) -> io::Result<()> {
+        if let Some(wal) = &self.wal {
+            wal.lock().unwrap().sync()?;
+        }
+
+        match self.data.storage() {
+            Storage::Heap(_) => Ok(()),
+            #[cfg(feature = "mmap")]
+            Storage::Mapped(cells) => cells.flush(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot<K, V, M, C>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let state = Arc::new(SnapshotState::new(Arc::clone(&self.live_snapshots)));
+        self.snapshots.lock().unwrap().push(Arc::downgrade(&state));
+
+        let index = Self::generate_index(Arc::clone(&self.data), Arc::clone(&self.comparator));
+        Snapshot::new(index, state)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.try_insert(key, value).unwrap();
+    }
+
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<(), Error> {
+        self.insert_growing(key, value, true).map(drop)
+    }
+
+    // Inserts the entry unless the key is already there, either way returning
+    // the value that's in the map
+    pub fn get_or_insert_atomic(&mut self, key: K, value: V) -> Result<&V, Error> {
+        let (cell, _) = self.insert_growing(key, value, false)?;
+        Ok(unsafe { cell.entry() }.unwrap().1)
+    }
+
+    // Out of room where the key belongs, the map moves into an array sized for
+    // twice as many keys and tries again
+    fn insert_growing(
+        &mut self,
+        key: K,
+        value: V,
+        replace_existing: bool,
+    ) -> Result<(&Cell<K, V>, Option<V>), Error> {
+        let mut entry = Some((key, value));
+        loop {
+            // let go of the borrow, growing needs the map to itself
+            let result = self
+                .insert_cell(&mut entry, replace_existing)
+                .map(|(cell, previous)| (cell as *const Cell<K, V>, previous));
+            match result {
+                Err(Error::CapacityExhausted) => {
+                    let capacity = self.data.requested_capacity as usize * 2;
+                    self.try_reserve(capacity - self.len().min(capacity))?;
+                }
+                result => return result.map(|(cell, previous)| (unsafe { &*cell }, previous)),
+            }
+        }
+    }
+
+    // Makes room for `additional` more keys than the map holds, repacking it
+    // into a larger array if the current one wasn't sized for them
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), Error> {
+        let capacity: u32 = self
+            .len()
+            .checked_add(additional)
+            .and_then(|capacity| capacity.try_into().ok())
+            .ok_or(Error::CapacityExhausted)?;
+        if capacity <= self.data.requested_capacity {
+            return Ok(());
+        }
+        // a file-backed array can't grow past the file it was opened with
+        if self.data.is_mapped() {
+            return Err(Error::CapacityExhausted);
+        }
+
+        // nothing moves until the new array and its index nodes are in hand,
+        // so a failed allocation leaves the map as it was
+        let packed_cells = PackedMemoryArray::try_with_capacity(capacity, self.data.allocator())?;
+        let nodes = BlockSearchTree::try_allocate(packed_cells.len(), packed_cells.allocator())?;
+
+        let mut index = self.index.write().map_err(|_| Error::Poisoned)?;
+        self.index_generation.fetch_add(1, AtomicOrdering::AcqRel);
+        let entries = self.take_entries(self.data.active_range.start);
+        self.snapshots.lock().unwrap().clear();
+
+        Self::spread(&packed_cells, entries);
+        self.data = Arc::new(packed_cells);
+        *index =
+            Self::generate_index_in(Arc::clone(&self.data), Arc::clone(&self.comparator), nodes);
+        Ok(())
+    }
+
+    pub fn compare_exchange<Q: ?Sized>(
+        &mut self,
+        key: &Q,
+        current: &V,
+        new: V,
+    ) -> Result<V, Option<V>>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        V: Clone + PartialEq,
+    {
+        self.fetch_update(key, |value| {
+            if value == current {
+                Some(new.clone())
             } else {
-                let cache = cell_guard.cache().unwrap().clone().unwrap();
-                if Key::Value(&cache.key) < Key::Value(&key) {
-                    selected_cell = Some(cell_guard);
+                None
+            }
+        })
+    }
+
+    // Replaces the value with what `f` makes of it, handing back the one it
+    // replaced. The current value is cloned only when `f` declines to replace it.
+    pub fn fetch_update<Q: ?Sized, F>(&mut self, key: &Q, mut f: F) -> Result<V, Option<V>>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        V: Clone,
+        F: FnMut(&V) -> Option<V>,
+    {
+        loop {
+            let mut cell_guard = self.find_cell(key).ok_or(None)?;
+            let update = cell_guard
+                .read_with(|entry| entry.map(|(_, value)| f(value).ok_or_else(|| value.clone())));
+            let new_value = match update {
+                Ok(Some(update)) => update.map_err(Some)?,
+                Ok(None) => return Err(None),
+                // Cell is mid-update, read it again
+                Err(_) => {
+                    back_off();
                     continue;
-                } else if Key::Value(&cache.key) == Key::Value(&key) {
-                    selected_cell = None;
-                } else if selected_cell.is_none() {
-                    // we didn't find any cells that were <= our key, rebalance to make room
-                    // is_smallest_key = true;
-                    self.rebalance(cell_guard.inner as *const _, true);
                 }
-            }
+            };
 
-            if let Some(cell_to_move) = &selected_cell {
-                // move cell to make room for insert
-                self.rebalance(cell_to_move.inner, true);
+            let marker_version = cell_guard.cache_version.wrapping_add(1);
+            let marker = Marker::InsertCell(marker_version);
+            self.preserve_for_snapshots(cell_guard.inner);
+
+            let prev_marker = match cell_guard.update(marker) {
+                Ok(prev_marker) => prev_marker,
+                // Marker has been updated by another process, re-evaluate against the new value
+                Err(_) => {
+                    back_off();
+                    continue;
+                }
+            };
+            // the marker keeps other writers out, so the entry can be read directly
+            let (key, value) = Self::entry(cell_guard.inner);
+            if self.log(Record::Insert(key, &new_value)).is_err() {
+                // nothing was written, the cell goes back as it was
+                let value = value.clone();
+                Self::release_cell(cell_guard.inner, prev_marker, marker_version);
+                return Err(Some(value));
             }
 
-            let marker_version = cell_guard.cache_version + 1;
-            let cell = selected_cell.as_mut().unwrap_or(&mut cell_guard);
+            let (_, value) = unsafe { cell_guard.inner.entry_mut() }.unwrap();
+            let previous = mem::replace(value, new_value);
+            self.data.mark_written(cell_guard.inner);
+            Self::release_cell(cell_guard.inner, prev_marker, marker_version);
 
-            let marker = Marker::InsertCell(marker_version, key.clone(), value.clone());
+            return Ok(previous);
+        }
+    }
 
-            let result = cell.update(marker);
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.try_remove(key).unwrap()
+    }
 
-            if result.is_err() {
-                // Marker has been updated by another process, start loop over
+    // Like `remove`, but an error rather than a panic when a durable map can't log it
+    pub fn try_remove<Q: ?Sized>(&mut self, key: &Q) -> Result<Option<V>, Error>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let Some(cell_guard) = self.find_cell(key) else {
+            return Ok(None);
+        };
+        let entry = self.remove_cell(cell_guard)?;
+        self.request_reindex();
+        Ok(entry.map(|(_, value)| value))
+    }
+
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        let cell_guard = self.index.read().unwrap().first_cell()?;
+        Some(Self::entry(cell_guard.inner))
+    }
+
+    // Number of keys smaller than `key`
+    pub fn rank<Q: ?Sized>(&self, key: &Q) -> usize
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.index.read().unwrap().rank(key)
+    }
+
+    // The nth smallest entry, counting from zero
+    pub fn select(&self, n: usize) -> Option<(&K, &V)> {
+        let cell_guard = self.index.read().unwrap().select(n)?;
+        Some(Self::entry(cell_guard.inner))
+    }
+
+    // Combines the `M` aggregate of every entry in the range
+    pub fn aggregate<Q: ?Sized, R>(&self, range: R) -> M::Summary
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
+    {
+        self.index.read().unwrap().aggregate(&range)
+    }
+
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        let cell_guard = self.last_cell()?;
+        Some(Self::entry(cell_guard.inner))
+    }
+
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        self.try_pop_first().unwrap()
+    }
+
+    // Like `pop_first`, but an error rather than a panic when a durable map can't log it
+    pub fn try_pop_first(&mut self) -> Result<Option<(K, V)>, Error> {
+        let Some(cell_guard) = self.index.read().map_err(|_| Error::Poisoned)?.first_cell() else {
+            return Ok(None);
+        };
+        let entry = self.remove_cell(cell_guard)?;
+        self.request_reindex();
+        Ok(entry)
+    }
+
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        self.try_pop_last().unwrap()
+    }
+
+    pub fn try_pop_last(&mut self) -> Result<Option<(K, V)>, Error> {
+        let Some(cell_guard) = self.last_cell() else {
+            return Ok(None);
+        };
+        let entry = self.remove_cell(cell_guard)?;
+        self.request_reindex();
+        Ok(entry)
+    }
+
+    pub fn comparator(&self) -> &C {
+        &self.comparator
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(AtomicOrdering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Runs `f` until the cells it read are unchanged when its writes land,
+    // giving up with `Contended` after `MAX_TRANSACTION_ATTEMPTS`. Either all
+    // of the writes land or, when one of them fails, none of them.
+    pub fn transaction<F, T>(&mut self, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut(&mut Transaction<'_, K, V, M, C>) -> T,
+        K: Clone,
+        V: Clone,
+    {
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            let mut transaction = Transaction::new(self);
+            let result = f(&mut transaction);
+
+            // Nothing has been written yet, so on conflict we can simply run it again
+            let Some(writes) = transaction.validate() else {
                 continue;
+            };
+            self.commit(writes)?;
+            return Ok(result);
+        }
+
+        Err(Error::Contended)
+    }
+
+    // Writes a transaction's entries, undoing those already written if one fails
+    fn commit(&mut self, writes: Vec<(K, Option<V>)>) -> Result<(), Error>
+    where
+        K: Clone,
+    {
+        let mut written = Vec::with_capacity(writes.len());
+        for (key, value) in writes {
+            match self.write_entry(key.clone(), value) {
+                Ok(previous) => written.push((key, previous)),
+                Err(error) => {
+                    self.undo(written, error);
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Puts back what a failed commit wrote, in reverse. A log that failed
+    // takes no more records, so the undo then only reaches memory: the map
+    // refuses writes from here on and recovery replays what the log took.
+    // An undo that fails would leave the commit half done, so it panics.
+    fn undo(&mut self, written: Vec<(K, Option<V>)>, error: Error) {
+        #[cfg(feature = "std")]
+        let wal = match error {
+            Error::Io(_) => self.wal.take(),
+            _ => None,
+        };
+        #[cfg(not(feature = "std"))]
+        let _ = error;
+
+        for (key, previous) in written.into_iter().rev() {
+            if let Err(error) = self.write_entry(key, previous) {
+                panic!("Failed to undo a transaction write: {}", error);
+            }
+        }
+
+        #[cfg(feature = "std")]
+        if wal.is_some() {
+            self.wal = wal;
+        }
+    }
+
+    // Sets the key's value, or removes it for `None`, returning what it held
+    fn write_entry(&mut self, key: K, value: Option<V>) -> Result<Option<V>, Error> {
+        match value {
+            Some(value) => {
+                let (_, previous) = self.insert_growing(key, value, true)?;
+                Ok(previous)
+            }
+            None => self.try_remove(&key),
+        }
+    }
+
+    pub(super) fn find_cell<Q: ?Sized>(&self, key: &Q) -> Option<CellGuard<'_, K, V>>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.try_find_cell(key).unwrap()
+    }
+
+    // Guards on the cells an insert of the missing `key` could land in, or
+    // `None` if it's there after all
+    pub(super) fn gap_cells<Q: ?Sized>(&self, key: &Q) -> Option<Vec<CellGuard<'_, K, V>>>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.index.read().unwrap().gap_cells(key)
+    }
+
+    fn try_find_cell<Q: ?Sized>(&self, key: &Q) -> Result<Option<CellGuard<'_, K, V>>, Error>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.index
+            .read()
+            .map_err(|_| Error::Poisoned)?
+            .find_cell(key)
+    }
+
+    pub fn generate_index(
+        data: Arc<PackedMemoryArray<Cell<K, V>>>,
+        comparator: Arc<C>,
+    ) -> BlockIndex<K, V, M, C> {
+        let written = data.write_sequence();
+        BlockIndex {
+            map: Arc::clone(&data),
+            index_tree: BlockSearchTree::new(data),
+            comparator,
+            written,
+        }
+    }
+
+    // Builds the index into nodes allocated ahead of time
+    fn generate_index_in(
+        data: Arc<PackedMemoryArray<Cell<K, V>>>,
+        comparator: Arc<C>,
+        nodes: NodeMemory<K, V, M>,
+    ) -> BlockIndex<K, V, M, C> {
+        let written = data.write_sequence();
+        BlockIndex {
+            map: Arc::clone(&data),
+            index_tree: BlockSearchTree::build(nodes, data),
+            comparator,
+            written,
+        }
+    }
+
+    // The caller requests the reindex, once it has let go of any index lock
+    fn remove_cell(&self, mut cell_guard: CellGuard<'_, K, V>) -> Result<Option<(K, V)>, Error> {
+        let marker_version = cell_guard.cache_version.wrapping_add(1);
+        let marker = Marker::DeleteCell(marker_version);
+        self.preserve_for_snapshots(cell_guard.inner);
+
+        // Marker has been updated by another process, the key is no longer ours to remove
+        let prev_marker = match cell_guard.update(marker) {
+            Ok(prev_marker) => prev_marker,
+            Err(_) => return Ok(None),
+        };
+        let key = match unsafe { cell_guard.inner.key() } {
+            Some(key) => key,
+            // emptied before the guard was taken
+            None => {
+                Self::release_cell(cell_guard.inner, prev_marker, marker_version);
+                return Ok(None);
             }
+        };
+        if let Err(error) = self.log(Record::Remove(key)) {
+            Self::release_cell(cell_guard.inner, prev_marker, marker_version);
+            return Err(error);
+        }
+
+        let entry = unsafe { cell_guard.inner.take() };
+        if entry.is_some() {
+            self.len.fetch_sub(1, AtomicOrdering::AcqRel);
+        }
 
-            let prev_marker = result.unwrap();
+        self.data.mark_written(cell_guard.inner);
+        Self::release_cell(cell_guard.inner, prev_marker, marker_version);
+
+        Ok(entry)
+    }
+
+    fn last_cell(&self) -> Option<CellGuard<'_, K, V>> {
+        let mut address = self.data.active_range.end;
+        while address >= self.data.active_range.start {
+            let cell_guard = unsafe { CellGuard::from_raw(address).unwrap() };
+            if !cell_guard.is_empty() {
+                return Some(cell_guard);
+            }
+            address = unsafe { address.sub(1) };
+        }
+
+        None
+    }
+
+    fn entry(cell: &Cell<K, V>) -> (&K, &V) {
+        unsafe { cell.entry() }.unwrap()
+    }
+
+    // Returns the cell holding the entry's key once the write has landed, and
+    // the value it replaced. An existing entry is left untouched unless
+    // `replace_existing` is set. The entry is only taken once it's written, so
+    // on error the caller still has it.
+    fn insert_cell(
+        &self,
+        entry: &mut Option<(K, V)>,
+        replace_existing: bool,
+    ) -> Result<(&Cell<K, V>, Option<V>), Error> {
+        let index = self.index.read().map_err(|_| Error::Poisoned)?;
+        let block = match index.get_block_for_insert(&entry.as_ref().unwrap().0) {
+            SearchResult::Block(block) => block,
+            _ => return Err(Error::CapacityExhausted),
+        };
+
+        let mut attempts = 0;
+        let inserted = loop {
+            if attempts == MAX_RETRIES {
+                return Err(Error::Contended);
+            }
+            attempts += 1;
+
+            let (key, value) = entry.as_ref().unwrap();
+            let mut cell = self
+                .find_insert_position(block.cell_slice_ptr, key)?
+                .ok_or(Error::CapacityExhausted)?;
+
+            let existing = unsafe { cell.inner.key() };
+            if !replace_existing
+                && existing.is_some_and(|k| self.comparator.compare(k, key) == Ordering::Equal)
+            {
+                return Ok((cell.inner, None));
+            }
+
+            let marker_version = cell.cache_version.wrapping_add(1);
+            let marker = Marker::InsertCell(marker_version);
+            self.preserve_for_snapshots(cell.inner);
+
+            let prev_marker = match cell.update(marker) {
+                Ok(prev_marker) => prev_marker,
+                // Marker has been updated by another process, start loop over
+                Err(_) => {
+                    back_off();
+                    continue;
+                }
+            };
+            if let Err(error) = self.log(Record::Insert(key, value)) {
+                Self::release_cell(cell.inner, prev_marker, marker_version);
+                return Err(error);
+            }
 
             // We now have exclusive access to the cell until we update `version`.
             // This works well for mutating through UnsafeCell<T>, but isn't really
             // "lock-free"...
-            unsafe {
-                cell.inner.key.get().write(Some(key));
-                cell.inner.value.get().write(Some(value));
+            if existing.is_none() {
+                self.len.fetch_add(1, AtomicOrdering::AcqRel);
+            }
+            let (key, value) = entry.take().unwrap();
+            let previous = unsafe { cell.inner.put(key, value) };
+
+            self.data.mark_written(cell.inner);
+            Self::release_cell(cell.inner, prev_marker, marker_version);
+
+            break (cell.inner, previous);
+        };
+
+        drop(index);
+        self.request_reindex();
+        Ok(inserted)
+    }
+
+    fn find_insert_position<'a>(
+        &'a self,
+        block_start: *const Cell<K, V>,
+        key: &K,
+    ) -> Result<Option<CellGuard<'a, K, V>>, Error> {
+        // Todo: Clean up (abstract out CellGuard)
+        let iter = self
+            .data
+            .into_iter()
+            .skip_while(|&x| !core::ptr::eq(x, block_start))
+            .map(|c| unsafe { CellGuard::from_raw(c).unwrap() });
+
+        // The index may be stale, so rather than trusting the block's min key
+        // we remember the first gap after the last key smaller than ours
+        let mut gap: Option<CellGuard<K, V>> = None;
+        for mut cell_guard in iter {
+            if cell_guard.is_empty() {
+                if gap.is_none() {
+                    gap = Some(cell_guard);
+                }
+                continue;
+            }
+
+            let order = cell_guard
+                .read(|entry| entry.map(|(cell_key, _)| self.comparator.compare(cell_key, key)))?;
+            let order = match order {
+                Some(order) => order,
+                // emptied since the guard was taken
+                None => continue,
             };
+            if order == Ordering::Less {
+                gap = None;
+            } else if order == Ordering::Equal {
+                return Ok(Some(cell_guard));
+            } else if gap.is_some() {
+                return Ok(gap);
+            } else {
+                // the first larger key sits where ours belongs,
+                // shift it rightward to make room
+                self.rebalance(cell_guard.inner as *const _, true)?;
+                return Ok(Some(unsafe { CellGuard::from_raw(cell_guard.inner) }?));
+            }
+        }
+
+        // no larger keys follow, take the gap after the last smaller key
+        Ok(gap)
+    }
+
+    // Publishes a finished write: the reused marker allocation goes back into
+    // the cell as `Empty`, and the version moves past the in-flight marker's
+    fn release_cell(cell: &Cell<K, V>, prev_marker: *mut Marker, marker_version: u16) {
+        let next_version = marker_version.wrapping_add(1);
+
+        let prev_marker = unsafe { Marker::reuse(prev_marker, Marker::Empty(next_version)) };
+        let in_flight_marker = cell
+            .marker
+            .as_ref()
+            .unwrap()
+            .swap(prev_marker, AtomicOrdering::SeqCst);
+        cell.version.swap(next_version, AtomicOrdering::SeqCst);
+
+        unsafe { Marker::free(in_flight_marker) };
+    }
+
+    fn end_ptr(&self) -> *const Cell<K, V> {
+        // active_range.end is the last usable cell
+        unsafe { self.data.active_range.end.add(1) }
+    }
+
+    fn preserve_for_snapshots(&self, cell_ptr: *const Cell<K, V>) {
+        // writers have the map to themselves, so none can be taken meanwhile
+        if self.live_snapshots.load(AtomicOrdering::Acquire) == 0 {
+            return;
+        }
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.retain(|state| state.strong_count() > 0);
 
-            let next_version = marker_version + 1;
+        let offset = self.data.index_of(cell_ptr);
+        for state in snapshots.iter().filter_map(Weak::upgrade) {
+            state.preserve(offset, unsafe { &*cell_ptr });
+        }
+    }
 
-            // Reuse previous marker allocation
-            unsafe { prev_marker.write(Marker::Empty(next_version)) };
-            cell.inner
-                .marker
-                .as_ref()
-                .unwrap()
-                .swap(prev_marker, Ordering::SeqCst);
-            cell.inner.version.swap(next_version, Ordering::SeqCst);
+    // Appends to the log of a durable map, first folding it into a checkpoint
+    // if it has grown long enough. Called once a write is certain to land but
+    // before it does, so the checkpoint never holds writes the log doesn't.
+    // A write that can't be logged must not land.
+    fn log(&self, record: Record<&K, &V>) -> Result<(), Error> {
+        self.log_all(iter::once(record))
+    }
 
-            break;
+    // Logs a batch of writes before any of them lands, stopping at the first
+    // record the log refuses. Only the log from before the batch is folded
+    // into a checkpoint, since the batch's writes aren't in the cells yet.
+    #[cfg(feature = "std")]
+    fn log_all<'a>(&self, records: impl Iterator<Item = Record<&'a K, &'a V>>) -> Result<(), Error>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock().map_err(|_| Error::Poisoned)?;
+            if wal.needs_checkpoint() {
+                wal.checkpoint(&self.data)
+                    .map_err(|error| Error::Io(error.kind()))?;
+            }
+            for record in records {
+                wal.append(record)
+                    .map_err(|error| Error::Io(error.kind()))?;
+            }
         }
+        Ok(())
+    }
 
-        self.request_reindex();
+    #[cfg(not(feature = "std"))]
+    fn log_all<'a>(&self, _records: impl Iterator<Item = Record<&'a K, &'a V>>) -> Result<(), Error>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        Ok(())
     }
 
-    pub fn generate_index(data: Arc<PackedMemoryArray<Cell<K, V>>>) -> BlockIndex<K, V> {
-        BlockIndex {
-            map: Arc::clone(&data),
-            index_tree: BlockSearchTree::new(data),
-        }
+    fn log_inserts<'a>(&self, entries: impl Iterator<Item = (&'a K, &'a V)>) -> Result<(), Error> {
+        self.log_all(entries.map(|(key, value)| Record::Insert(key, value)))
+    }
+
+    fn log_removes<'a>(&self, entries: impl Iterator<Item = (&'a K, &'a V)>) -> Result<(), Error> {
+        self.log_all(entries.map(|(key, _)| Record::Remove(key)))
     }
 
+    #[cfg(feature = "std")]
     fn request_reindex(&self) {
-        // debounce
-        if !self.index_updating.load(Ordering::Acquire) {
-            let _ = self.tx.send(Arc::downgrade(&self.data));
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+
+        // debounce, a request is already waiting for the indexing thread
+        if !self.index_updating.swap(true, AtomicOrdering::AcqRel) {
+            let _ = tx.send(Arc::downgrade(&self.data));
         }
     }
 
+    // Without a thread to hand the work to, the index is rebuilt before the write returns
+    #[cfg(not(feature = "std"))]
+    fn request_reindex(&self) {
+        let mut index = self.index.write().unwrap();
+        *index = Self::generate_index(Arc::clone(&self.data), Arc::clone(&self.comparator));
+        index.map.forget_writes(index.written);
+    }
+
+    #[cfg(feature = "std")]
     fn start_indexing_thread(
-        index: Arc<RwLock<BlockIndex<K, V>>>,
+        index: Arc<RwLock<BlockIndex<K, V, M, C>>>,
+        generation: Arc<AtomicUsize>,
         rx: Receiver<Weak<PackedMemoryArray<Cell<K, V>>>>,
-    ) -> Arc<AtomicBool> {
+    ) -> Result<(Arc<AtomicBool>, thread::JoinHandle<()>), Error> {
         let is_updating = Arc::new(AtomicBool::new(false));
         let thread_is_updating = Arc::clone(&is_updating);
-        thread::spawn(move || {
+        let spawned = thread::Builder::new().spawn(move || {
             loop {
                 let result = rx
                     .recv()
                     .ok()
-                    .map(|x| {
-                        thread_is_updating.store(true, Ordering::Release);
-                        x
+                    .inspect(|_| {
+                        // writes from here on need another pass
+                        thread_is_updating.store(false, AtomicOrdering::Release);
                     })
                     .and_then(|cells_ptr| cells_ptr.upgrade())
-                    .map(|cells| {
-                        let new_index = Self::generate_index(cells);
+                    .map(|_cells| {
+                        // holding a read lock keeps `redistribute` from moving cells underneath
+                        // us, and the cells may have been swapped out since the request was sent
+                        let (start_generation, new_index) = {
+                            let i = index.read().unwrap();
+                            let start_generation = generation.load(AtomicOrdering::Acquire);
+                            let new_index =
+                                Self::generate_index(Arc::clone(&i.map), Arc::clone(&i.comparator));
+                            (start_generation, new_index)
+                        };
                         let mut i = index.write().unwrap();
-                        // todo is the memory barrier in the right place here, relative to the index generation?
-                        thread_is_updating.store(false, Ordering::Release);
-                        *i = new_index;
+                        // cells may have moved leftwards since, which a stale index can't cope with
+                        if generation.load(AtomicOrdering::Acquire) == start_generation {
+                            *i = new_index;
+                            i.map.forget_writes(i.written);
+                        }
                     });
 
-                if let None = result {
+                if result.is_none() {
                     break;
                 }
 
-                thread::sleep(INDEX_UPDATE_DELAY);
+                // woken early when the map shuts the thread down
+                thread::park_timeout(INDEX_UPDATE_DELAY);
             }
         });
 
-        is_updating
+        let handle = spawned.map_err(|_| Error::AllocationFailed)?;
+        Ok((is_updating, handle))
     }
 
-    fn rebalance(&self, cell_ptr_start: *const Cell<K, V>, for_insertion: bool) {
-        let mut count = 1;
+    fn rebalance(
+        &self,
+        cell_ptr_start: *const Cell<K, V>,
+        for_insertion: bool,
+    ) -> Result<(), Error> {
         let mut cells_to_move: VecDeque<*const Cell<K, V>> = VecDeque::new();
         let mut current_cell_ptr = cell_ptr_start;
+        let mut within_density = false;
 
         let vec = self
             .data
             .into_iter()
-            .skip_while(|&x| x as *const _ != cell_ptr_start)
+            .skip_while(|&x| !core::ptr::eq(x, cell_ptr_start))
             .map(|c| unsafe { CellGuard::from_raw(c).unwrap() })
             .collect::<Vec<_>>();
 
-        for cell_guard in vec {
+        for (count, cell_guard) in (1usize..).zip(vec) {
             current_cell_ptr = cell_guard.inner;
 
             if !cell_guard.is_empty() {
@@ -220,10 +1228,14 @@ where
                 Rational::new(numer.try_into().unwrap(), count.try_into().unwrap());
 
             if self.within_density_threshold(count, current_density) {
+                within_density = true;
                 break;
             }
+        }
 
-            count += 1;
+        // every cell up to the end of the array is too full to shift into
+        if !within_density {
+            return Err(Error::CapacityExhausted);
         }
 
         // There are different strategies available for rebalancing
@@ -235,126 +1247,700 @@ where
         // this # of cells to meet our density, one less than this
         // should let each region maximize their respective thresholds.
 
-        // Starting at current_marker_raw, move the cells rightward
-        // until their max density is reached
+        // Starting at current_marker_raw, move the cells rightward
+        // until their max density is reached
+
+        // TODO: Can we use #compare_and_swap for neighbouring cells
+        // to make sure we don't leave any cells unallocated?
+
+        for cell_ptr in cells_to_move.iter() {
+            // the entries already at the right of the window stay put
+            if core::ptr::eq(*cell_ptr, current_cell_ptr) {
+                current_cell_ptr = unsafe { current_cell_ptr.sub(1) };
+                continue;
+            }
+
+            let cell = unsafe { &*current_cell_ptr };
+            if cell.is_filled() {
+                // TODO: I think we can overwrite these records since their contents have been moved...
+            }
+
+            let cell_to_move = unsafe { &**cell_ptr };
+            let version = cell_to_move.version.load(AtomicOrdering::SeqCst);
+            let current_marker_raw = cell_to_move
+                .marker
+                .as_ref()
+                .unwrap()
+                .load(AtomicOrdering::SeqCst);
+            let marker = unsafe { &*current_marker_raw };
+            let marker_version = *marker.version();
+
+            if version != marker_version {
+                return Err(Error::Contended);
+            }
+
+            // todo: self.data.into_iter() seems suspicious here
+            let dest_index = unsafe {
+                current_cell_ptr
+                    .offset_from(self.data.into_iter().next().unwrap() as *const Cell<K, V>)
+            };
+            let new_marker = Box::new(Marker::Move(marker_version, dest_index));
+
+            let new_marker_raw = Box::into_raw(new_marker);
+            let prev_marker = cell_to_move.marker.as_ref().unwrap().compare_exchange(
+                current_marker_raw,
+                new_marker_raw,
+                AtomicOrdering::SeqCst,
+                AtomicOrdering::SeqCst,
+            );
+
+            if prev_marker.is_err() {
+                // Marker has been updated by another process.
+                // Deallocate memory, start loop over.
+                drop(unsafe { Box::from_raw(new_marker_raw) });
+                return Err(Error::Contended);
+            }
+
+            // snapshots still need to see both cells as they were
+            self.preserve_for_snapshots(cell);
+            self.preserve_for_snapshots(cell_to_move);
+
+            // update new cell, moving the entry rather than copying it, and
+            // the old cell no longer owns what it held
+            unsafe { cell.move_from(cell_to_move) };
+            cell.bump_version();
+            self.data.mark_written(cell);
+            self.data.mark_written(cell_to_move);
+            // nobody else can touch the cell while our move marker is in it
+            Self::release_cell(cell_to_move, prev_marker.unwrap(), marker_version);
+
+            current_cell_ptr = unsafe { current_cell_ptr.sub(1) };
+            if !self.data.is_valid_pointer(&current_cell_ptr) {
+                // moved up against the end of the array with no room left
+                return Err(Error::CapacityExhausted);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Spreads the entries evenly over the whole array in a single pass, then
+    // rebuilds the index. Cells may move leftwards here, so no index built
+    // before the move may be installed afterwards.
+    fn redistribute(&mut self) {
+        let mut index = self.index.write().unwrap();
+        self.index_generation.fetch_add(1, AtomicOrdering::AcqRel);
+
+        for cell in self.data.into_iter() {
+            self.preserve_for_snapshots(cell);
+        }
+
+        let entries = self.take_entries(self.data.active_range.start);
+        self.len.store(entries.len(), AtomicOrdering::Release);
+        Self::spread(&self.data, entries);
+
+        *index = Self::generate_index(Arc::clone(&self.data), Arc::clone(&self.comparator));
+    }
+
+    // Spreads entries already taken out of the array back over it
+    fn respread(&mut self, entries: Vec<(K, V)>) {
+        let mut index = self.index.write().unwrap();
+        self.index_generation.fetch_add(1, AtomicOrdering::AcqRel);
+
+        for cell in self.data.into_iter() {
+            self.preserve_for_snapshots(cell);
+        }
+        self.len.store(entries.len(), AtomicOrdering::Release);
+        Self::spread(&self.data, entries);
+
+        *index = Self::generate_index(Arc::clone(&self.data), Arc::clone(&self.comparator));
+    }
+
+    // Swaps in an array holding `entries` with at least the capacity of the
+    // current one. Snapshots keep the old array, which is never written to again.
+    fn replace_cells(&mut self, entries: Vec<(K, V)>) {
+        // a file-backed array stays where it is, the entries are laid out in it again
+        if self.data.is_mapped() {
+            assert!(
+                entries.len() <= self.data.as_slice().len(),
+                "file-backed map is out of cells"
+            );
+            return self.respread(entries);
+        }
+
+        let mut index = self.index.write().unwrap();
+        self.index_generation.fetch_add(1, AtomicOrdering::AcqRel);
+        self.snapshots.lock().unwrap().clear();
+
+        // room for as many again if the entries outgrow the array
+        let capacity = (entries.len() as u32)
+            .saturating_mul(2)
+            .max(self.data.requested_capacity);
+        self.len.store(entries.len(), AtomicOrdering::Release);
+        self.data = Arc::new(Self::pack(entries, capacity, self.data.allocator()));
+        *index = Self::generate_index(Arc::clone(&self.data), Arc::clone(&self.comparator));
+    }
+
+    // Repacks the map with sorted, deduplicated entries merged in, the new
+    // entries replacing any with equal keys. The caller logs them.
+    fn merge_entries(&mut self, theirs: Vec<(K, V)>) {
+        let ours = self.take_entries(self.data.active_range.start);
+
+        let mut merged = Vec::with_capacity(ours.len() + theirs.len());
+        let mut ours = ours.into_iter().peekable();
+        for (key, value) in theirs {
+            while let Some((ours_key, _)) = ours.peek() {
+                if self.comparator.compare(ours_key, &key) == Ordering::Greater {
+                    break;
+                }
+                let entry = ours.next().unwrap();
+                if self.comparator.compare(&entry.0, &key) == Ordering::Less {
+                    merged.push(entry);
+                }
+            }
+            merged.push((key, value));
+        }
+        merged.extend(ours);
+
+        self.replace_cells(merged);
+    }
+
+    // Sorts entries by key, keeping the last value given for each key
+    fn sorted_entries<I>(iter: I, comparator: &C) -> Vec<(K, V)>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut entries = iter.into_iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| comparator.compare(&a.0, &b.0));
+        entries.dedup_by(|later, earlier| {
+            if comparator.compare(&later.0, &earlier.0) == Ordering::Equal {
+                core::mem::swap(later, earlier);
+                true
+            } else {
+                false
+            }
+        });
+        entries
+    }
+
+    // Empties every cell from `start` to the end of the array, in order
+    fn take_entries(&self, start: *const Cell<K, V>) -> Vec<(K, V)> {
+        let mut entries = Vec::new();
+        let mut address = start;
+
+        while address < self.end_ptr() {
+            let cell = unsafe { &*address };
+            if cell.is_filled() {
+                self.preserve_for_snapshots(cell);
+                let entry = unsafe { cell.take() };
+                cell.bump_version();
+                entries.extend(entry);
+            }
+            address = unsafe { address.add(1) };
+        }
+
+        entries
+    }
+
+    // Sized for `capacity` keys, or at least as many as there are entries
+    fn pack(
+        entries: Vec<(K, V)>,
+        capacity: u32,
+        allocator: Arc<dyn Allocator>,
+    ) -> PackedMemoryArray<Cell<K, V>> {
+        let capacity = capacity.max(entries.len() as u32).max(MIN_CAPACITY);
+        let packed_cells = PackedMemoryArray::with_capacity(capacity, allocator);
+        Self::spread(&packed_cells, entries);
+        packed_cells
+    }
+
+    // Writes sorted entries into evenly spaced cells of the active range,
+    // spaced as they would be with the array filled to its requested
+    // capacity so the cells past them are left for later inserts
+    fn spread(data: &PackedMemoryArray<Cell<K, V>>, entries: Vec<(K, V)>) {
+        let cells = data.into_iter().collect::<Vec<_>>();
+        let count = entries.len();
+        let span = (cells.len() as u64 * count as u64 / u64::from(data.requested_capacity.max(1)))
+            as usize;
+        let span = span.clamp(count, cells.len());
+
+        for (i, (key, value)) in entries.into_iter().enumerate() {
+            let cell = cells[i * span / count];
+            unsafe { cell.put(key, value) };
+        }
+    }
+
+    fn within_density_threshold(&self, num_items: usize, current_density: Ratio<isize>) -> bool {
+        let density = self
+            .data
+            .config
+            .density_scale
+            .iter()
+            .find(|d| d.max_item_count >= num_items)
+            .unwrap();
+
+        density.range.contains(&current_density)
+    }
+}
+
+impl<K, V, M, C> BTreeMap<K, V, M, C>
+where
+    M: Monoid<K, V>,
+    C: Comparator<K>,
+{
+    // Once this returns nothing but the map itself touches the cells
+    #[cfg(feature = "std")]
+    fn stop_indexing(&mut self) {
+        // without a sender the thread's loop ends as soon as it wakes
+        self.tx.take();
+        if let Some(indexer) = self.indexer.take() {
+            indexer.thread().unpark();
+            let _ = indexer.join();
+        }
+    }
+
+    // nothing else touches the cells when the index is rebuilt in place
+    #[cfg(not(feature = "std"))]
+    fn stop_indexing(&mut self) {}
+}
+
+impl<K, V, M, C> Drop for BTreeMap<K, V, M, C>
+where
+    M: Monoid<K, V>,
+    C: Comparator<K>,
+{
+    fn drop(&mut self) {
+        self.stop_indexing();
+    }
+}
+
+impl<K, V, M, C> Debug for BTreeMap<K, V, M, C>
+where
+    K: Copy + Debug,
+    V: Debug,
+    M: Monoid<K, V>,
+    C: Comparator<K>,
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("BTreeMap")
+            .field("data", &format_args!("{:?}", self.data))
+            .finish()
+    }
+}
+
+impl<K, V, M, C> Clone for BTreeMap<K, V, M, C>
+where
+    K: 'static + Clone,
+    V: 'static + Clone,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    // Same capacity and cell layout, with its own index thread
+    fn clone(&self) -> Self {
+        let packed_cells = PackedMemoryArray::<Cell<K, V>>::with_capacity(
+            self.data.requested_capacity,
+            self.data.allocator(),
+        );
+        for (source, cell) in self.data.into_iter().zip(&packed_cells) {
+            if let Some((key, value)) = unsafe { source.entry() } {
+                unsafe { cell.put(key.clone(), value.clone()) };
+            }
+        }
+
+        Self::from_packed_cells(packed_cells, Arc::clone(&self.comparator))
+    }
+}
+
+impl<K, V, M, C> PartialEq for BTreeMap<K, V, M, C>
+where
+    K: 'static + PartialEq,
+    V: 'static + PartialEq,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<K, V, M, C> Eq for BTreeMap<K, V, M, C>
+where
+    K: 'static + Eq,
+    V: 'static + Eq,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+}
+
+impl<K, V, M, C> PartialOrd for BTreeMap<K, V, M, C>
+where
+    K: 'static + PartialOrd,
+    V: 'static + PartialOrd,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<K, V, M, C> Ord for BTreeMap<K, V, M, C>
+where
+    K: 'static + Ord,
+    V: 'static + Ord,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl<K, V, M, C> Hash for BTreeMap<K, V, M, C>
+where
+    K: 'static + Hash,
+    V: 'static + Hash,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        for entry in self.iter() {
+            entry.hash(state);
+        }
+    }
+}
+
+impl<K, V, M, C> Default for BTreeMap<K, V, M, C>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync + Default,
+{
+    fn default() -> Self {
+        Self::with_monoid(DEFAULT_CAPACITY)
+    }
+}
+
+impl<K, V, M, C, Q: ?Sized> Index<&Q> for BTreeMap<K, V, M, C>
+where
+    K: 'static + Borrow<Q>,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+    C: Comparator<Q>,
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+// Extending repacks the map once rather than inserting entry by entry, so
+// the map grows with it
+impl<K, V, M, C> Extend<(K, V)> for BTreeMap<K, V, M, C>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    // Entries go in one at a time unless there are enough of them that
+    // repacking the whole map is cheaper
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let entries = Self::sorted_entries(iter, &*self.comparator);
+        if entries.len() < self.len() {
+            for (key, value) in entries {
+                self.insert(key, value);
+            }
+        } else {
+            self.log_inserts(entries.iter().map(|(key, value)| (key, value)))
+                .unwrap();
+            self.merge_entries(entries);
+        }
+    }
+}
+
+impl<K, V, M, C> FromIterator<(K, V)> for BTreeMap<K, V, M, C>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync + Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let comparator = C::default();
+        let entries = Self::sorted_entries(iter, &comparator);
+        // room for as many again before the map has to grow
+        let capacity = (entries.len() as u32)
+            .saturating_mul(2)
+            .max(DEFAULT_CAPACITY);
+        Self::from_sorted_entries(entries, capacity, Arc::new(comparator), allocator::global())
+    }
+}
+
+impl<K, V, M, C, const N: usize> From<[(K, V); N]> for BTreeMap<K, V, M, C>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync + Default,
+{
+    fn from(entries: [(K, V); N]) -> Self {
+        Self::from_iter(entries)
+    }
+}
+
+impl<K, V, M, C> IntoIterator for BTreeMap<K, V, M, C>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(mut self) -> IntoIter<K, V> {
+        // the indexing thread reads cells, it has to be gone before we move out of them
+        self.stop_indexing();
+
+        let data = Arc::clone(&self.data);
+        let snapshots = self
+            .snapshots
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let address = data.active_range.start;
+
+        IntoIter {
+            data,
+            address,
+            snapshots,
+        }
+    }
+}
+
+// Moves entries out of their cells as it goes. Snapshots still share the
+// cells, so each one is handed a copy before its cell is emptied.
+pub struct IntoIter<K, V> {
+    data: Arc<PackedMemoryArray<Cell<K, V>>>,
+    address: *const Cell<K, V>,
+    snapshots: Vec<Arc<SnapshotState<K, V>>>,
+}
+
+unsafe impl<K: Send, V: Send> Send for IntoIter<K, V> {}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.address <= self.data.active_range.end {
+            let cell = unsafe { &*self.address };
+            let offset = self.data.index_of(self.address);
+            self.address = unsafe { self.address.add(1) };
+
+            if cell.is_filled() {
+                for state in self.snapshots.iter() {
+                    state.preserve(offset, cell);
+                }
+                return unsafe { cell.take() };
+            }
+        }
+
+        None
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    address: *const Cell<K, V>,
+    end_address: *const Cell<K, V>,
+    _phantom: PhantomData<&'a Cell<K, V>>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn new(address: *const Cell<K, V>, end_address: *const Cell<K, V>) -> Iter<'a, K, V> {
+        Iter {
+            address,
+            end_address,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.address < self.end_address {
+            let cell = unsafe { &*self.address };
+            self.address = unsafe { self.address.add(1) };
+
+            if let Some(entry) = unsafe { cell.entry() } {
+                return Some(entry);
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.address < self.end_address {
+            self.end_address = unsafe { self.end_address.sub(1) };
+            let cell = unsafe { &*self.end_address };
+
+            if let Some(entry) = unsafe { cell.entry() } {
+                return Some(entry);
+            }
+        }
+
+        None
+    }
+}
 
-        // TODO: Can we use #compare_and_swap for neighbouring cells
-        // to make sure we don't leave any cells unallocated?
+pub struct IterMut<'a, K, V> {
+    address: *const Cell<K, V>,
+    end_address: *const Cell<K, V>,
+    _phantom: PhantomData<&'a mut Cell<K, V>>,
+}
 
-        for cell_ptr in cells_to_move.iter() {
-            let cell = unsafe { &*current_cell_ptr };
-            let cell_key = unsafe { &*cell.key.get() };
-            if cell_key.is_some() {
-                // TODO: I think we can overwrite these records since their contents have been moved...
-            }
+impl<'a, K, V> IterMut<'a, K, V> {
+    fn new(address: *const Cell<K, V>, end_address: *const Cell<K, V>) -> IterMut<'a, K, V> {
+        IterMut {
+            address,
+            end_address,
+            _phantom: PhantomData,
+        }
+    }
+}
 
-            let cell_to_move = unsafe { &**cell_ptr };
-            let version = cell_to_move.version.load(Ordering::SeqCst);
-            let current_marker_raw = cell_to_move.marker.as_ref().unwrap().load(Ordering::SeqCst);
-            let marker = unsafe { &*current_marker_raw };
-            let marker_version = *marker.version();
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
 
-            if version != marker_version {
-                todo!("Restart rebalance!");
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.address < self.end_address {
+            let cell = unsafe { &*self.address };
+            self.address = unsafe { self.address.add(1) };
+
+            if let Some(entry) = unsafe { cell.entry_mut() } {
+                return Some(entry);
             }
+        }
 
-            // todo: self.data.into_iter() seems suspicious here
-            let dest_index = unsafe {
-                current_cell_ptr
-                    .offset_from(self.data.into_iter().next().unwrap() as *const Cell<K, V>)
-            };
-            let new_marker = Box::new(Marker::Move(marker_version, dest_index));
+        None
+    }
+}
 
-            let new_marker_raw = Box::into_raw(new_marker);
-            let prev_marker = cell_to_move.marker.as_ref().unwrap().compare_exchange(
-                current_marker_raw,
-                new_marker_raw,
-                Ordering::SeqCst,
-                Ordering::SeqCst,
-            );
+impl<'a, K, V> DoubleEndedIterator for IterMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.address < self.end_address {
+            self.end_address = unsafe { self.end_address.sub(1) };
+            let cell = unsafe { &*self.end_address };
 
-            if prev_marker.is_err() {
-                // Marker has been updated by another process.
-                // Deallocate memory, start loop over.
-                unsafe { Box::from_raw(new_marker_raw) };
-                todo!("Restart rebalance!");
+            if let Some(entry) = unsafe { cell.entry_mut() } {
+                return Some(entry);
             }
+        }
 
-            unsafe {
-                // update new cell
-                cell.key.get().write((*cell_to_move.key.get()).clone());
-                cell.value.get().write((*cell_to_move.value.get()).clone());
-                // todo update version and marker of new cell?
-
-                // update old cell
-                cell_to_move.key.get().write(None);
-                cell_to_move.value.get().write(None);
-                let new_version = version + 1;
-                // reuse prev_marker box
-                prev_marker.unwrap().write(Marker::Empty(new_version));
-                // use compare_exchange_weak because we can safely fail here
-                let _ = cell_to_move.marker.as_ref().unwrap().compare_exchange_weak(
-                    new_marker_raw,
-                    prev_marker.unwrap(),
-                    Ordering::SeqCst,
-                    Ordering::SeqCst,
-                );
-                let _ = cell_to_move.version.compare_exchange_weak(
-                    marker_version,
-                    new_version,
-                    Ordering::SeqCst,
-                    Ordering::SeqCst,
-                );
-                // TODO: increment version, clear marker
+        None
+    }
+}
+
+pub struct ExtractIf<'a, K, V, M, C, F>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+    F: FnMut(&K, &mut V) -> bool,
+{
+    map: &'a mut BTreeMap<K, V, M, C>,
+    address: *const Cell<K, V>,
+    removed: usize,
+    // the log failure that stopped the iterator early
+    failed: Option<Error>,
+    predicate: F,
+}
+
+impl<'a, K, V, M, C, F> Iterator for ExtractIf<'a, K, V, M, C, F>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+    F: FnMut(&K, &mut V) -> bool,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.address <= self.map.data.active_range.end {
+            let cell = unsafe { &*self.address };
+            self.address = unsafe { self.address.add(1) };
+
+            let extract = match unsafe { cell.entry_mut() } {
+                Some((key, value)) => (self.predicate)(key, value),
+                None => false,
             };
 
-            current_cell_ptr = unsafe { current_cell_ptr.sub(1) };
-            if self.data.is_valid_pointer(&current_cell_ptr) {
-                continue;
-            } else {
-                unreachable!("We've reached the end of the initialized cell buffer!");
+            if extract {
+                // left in place, and nothing after it is looked at
+                if let Err(error) = self.map.log(Record::Remove(unsafe { cell.key() }.unwrap())) {
+                    self.failed = Some(error);
+                    self.address = unsafe { self.map.data.active_range.end.add(1) };
+                    return None;
+                }
+                self.map.preserve_for_snapshots(cell);
+                self.removed += 1;
+                self.map.len.fetch_sub(1, AtomicOrdering::AcqRel);
+                let entry = unsafe { cell.take() };
+                cell.bump_version();
+                self.map.data.mark_written(cell);
+                return entry;
             }
         }
-    }
-
-    fn within_density_threshold(&self, num_items: usize, current_density: Ratio<isize>) -> bool {
-        let density = self
-            .data
-            .config
-            .density_scale
-            .iter()
-            .find(|d| d.max_item_count >= num_items)
-            .unwrap();
 
-        density.range.contains(&current_density)
+        None
     }
 }
 
-impl<K, V> Debug for BTreeMap<K, V>
+impl<'a, K, V, M, C, F> Drop for ExtractIf<'a, K, V, M, C, F>
 where
-    K: Copy + Ord + Debug,
-    V: Clone + Debug,
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+    F: FnMut(&K, &mut V) -> bool,
 {
-    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-        formatter
-            .debug_struct("BTreeMap")
-            .field("data", &format_args!("{:?}", self.data))
-            .finish()
+    fn drop(&mut self) {
+        if self.removed > 0 {
+            self.map.redistribute();
+        }
     }
 }
 
-pub struct BlockIndex<K: Clone + Ord, V: Clone> {
-    map: Arc<PackedMemoryArray<Cell<K, V>>>,
-    index_tree: BlockSearchTree<K, V>,
+pub struct BlockIndex<K, V, M: Monoid<K, V> = (), C = OrdComparator> {
+    pub map: Arc<PackedMemoryArray<Cell<K, V>>>,
+    index_tree: BlockSearchTree<K, V, M>,
+    pub comparator: Arc<C>,
+    // the array's write sequence when the index was built
+    written: usize,
 }
 
-unsafe impl<K: Clone + Ord, V: Clone> Send for BlockIndex<K, V> {}
-unsafe impl<K: Clone + Ord, V: Clone> Sync for BlockIndex<K, V> {}
+unsafe impl<K, V, M: Monoid<K, V>, C: Send + Sync> Send for BlockIndex<K, V, M, C> {}
+unsafe impl<K, V, M: Monoid<K, V>, C: Send + Sync> Sync for BlockIndex<K, V, M, C> {}
 
-impl<K, V> Debug for BlockIndex<K, V>
+impl<K, V, M, C> Debug for BlockIndex<K, V, M, C>
 where
-    K: Clone + Ord + Debug,
-    V: Clone + Debug,
+    M: Monoid<K, V>,
+    K: Debug,
+    V: Debug,
 {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         formatter
@@ -365,137 +1951,427 @@ where
     }
 }
 
-impl<K, V> BlockIndex<K, V>
+impl<K, V, M, C> BlockIndex<K, V, M, C>
 where
-    K: Clone + Ord,
-    V: Clone,
+    M: Monoid<K, V>,
 {
-    fn get_block_for_insert<'a, Q>(&'a self, search_key: &Q) -> SearchResult<'a, K, V>
+    fn get_block_for_insert<'a, Q: ?Sized>(&'a self, search_key: &Q) -> SearchResult<'a, K, V, M>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.index_tree.find(search_key, true, &*self.comparator)
+    }
+
+    pub fn block_start<Q: ?Sized>(
+        &self,
+        search_key: &Q,
+        allow_empty: bool,
+    ) -> Option<*const Cell<K, V>>
     where
-        Q: Ord,
+        C: Comparator<Q>,
         K: Borrow<Q>,
     {
-        self.index_tree.find(search_key, true)
+        match self
+            .index_tree
+            .find(search_key, allow_empty, &*self.comparator)
+        {
+            SearchResult::Block(block) => Some(block.cell_slice_ptr),
+            _ => None,
+        }
     }
 
-    pub fn get<'a, Q>(&self, search_key: &Q) -> Option<&'a V>
+    pub fn get<'a, Q: ?Sized>(&self, search_key: &Q) -> Result<Option<&'a V>, Error>
     where
-        Q: Ord,
+        C: Comparator<Q>,
         K: Borrow<Q>,
     {
-        match self.index_tree.find(search_key, false) {
-            SearchResult::NotFound => return None,
-            SearchResult::Block(block, ..) => {
-                let iter = CellIterator::new(block.cell_slice_ptr, self.map.active_range.end);
+        let cell_guard = self.find_cell(search_key)?;
+        Ok(cell_guard.map(|cell_guard| {
+            // todo: ABA problem
+            unsafe { (*cell_guard.inner.value.get()).assume_init_ref() }
+        }))
+    }
 
-                for cell_guard in iter {
-                    if !cell_guard.is_empty() {
-                        let cache = cell_guard.cache().unwrap().clone().unwrap();
-                        let cache_key = cache.key.borrow();
-                        if cache_key == search_key {
-                            // todo: ABA problem
-                            let value =
-                                unsafe { (&*cell_guard.inner.value.get()).as_ref().unwrap() };
-                            return Some(value);
-                        } else if cache_key > search_key {
-                            return None;
-                        }
+    // Leaves written since the index was built and how many keys each has
+    // gained or lost since, in leaf order. Only these get recounted.
+    fn stale_leaves(&self) -> Vec<(usize, isize)> {
+        self.map
+            .written_since(self.written)
+            .into_iter()
+            .map(|leaf| {
+                let block = self.index_tree.leaf(leaf);
+                let cells = unsafe { slice::from_raw_parts(block.cell_slice_ptr, block.length) };
+                let count = cells.iter().filter(|cell| cell.is_filled()).count();
+                (leaf, count as isize - block.count as isize)
+            })
+            .collect()
+    }
+
+    pub fn rank<Q: ?Sized>(&self, search_key: &Q) -> usize
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let (block, preceding) = self.index_tree.rank(search_key, &*self.comparator);
+        let leaf = self.map.block_of(block.cell_slice_ptr);
+        let preceding = self
+            .stale_leaves()
+            .into_iter()
+            .take_while(|&(stale, _)| stale < leaf)
+            .fold(preceding as isize, |preceding, (_, change)| {
+                preceding + change
+            });
+        let iter = CellIterator::new(block.cell_slice_ptr, self.map.active_range.end);
+
+        let smaller_in_block = iter
+            .filter_map(|mut cell_guard| {
+                cell_guard
+                    .read(|entry| {
+                        entry.map(|(key, _)| {
+                            self.comparator.compare(key.borrow(), search_key) == Ordering::Less
+                        })
+                    })
+                    .unwrap()
+            })
+            .take_while(|&smaller| smaller)
+            .count();
+
+        preceding as usize + smaller_in_block
+    }
+
+    pub fn select<'a>(&self, n: usize) -> Option<CellGuard<'a, K, V>> {
+        let (block, position) = self.index_tree.select(n, &self.stale_leaves());
+        let iter = CellIterator::new(block.cell_slice_ptr, self.map.active_range.end);
+
+        iter.filter(|cell_guard| !cell_guard.is_empty())
+            .nth(position)
+    }
+
+    // Whole blocks inside the range contribute the aggregate cached when the
+    // index was built, unless they've been written since. Those and the
+    // blocks at either end are read cell by cell. The start is found by
+    // scanning, as routing on a stale min key can land blocks early.
+    pub fn aggregate<Q: ?Sized, R>(&self, range: &R) -> M::Summary
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
+    {
+        let first_cell = match range.start_bound() {
+            Bound::Included(key) => self.position_after(key, false),
+            Bound::Excluded(key) => self.position_after(key, true),
+            Bound::Unbounded => self.map.active_range.start,
+        };
+        if first_cell > self.map.active_range.end {
+            return M::identity();
+        }
+        let end = match range.end_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Key::Value(key),
+            Bound::Unbounded => Key::Supremum,
+        };
+
+        let end_block = self.index_tree.block_for(end, &*self.comparator);
+        let first_leaf = self.map.block_of(first_cell);
+        let last_leaf = self.map.block_of(end_block.cell_slice_ptr);
+        if first_leaf >= last_leaf {
+            return self.aggregate_cells(first_cell, self.map.active_range.end, range);
+        }
+
+        let first_block_end = unsafe {
+            self.map
+                .active_range
+                .start
+                .add((first_leaf + 1) * self.map.block_len() - 1)
+        };
+        let start_aggregate = self.aggregate_cells(first_cell, first_block_end, range);
+        let end_aggregate =
+            self.aggregate_cells(end_block.cell_slice_ptr, self.map.active_range.end, range);
+
+        let stale = self.map.written_since(self.written);
+        let between =
+            self.index_tree
+                .aggregate_leaves(first_leaf + 1..last_leaf, &stale, &|block| {
+                    self.aggregate_cells::<Q, _>(
+                        block.cell_slice_ptr,
+                        unsafe { block.cell_slice_ptr.add(block.length - 1) },
+                        &(..),
+                    )
+                });
+
+        M::combine(&M::combine(&start_aggregate, &between), &end_aggregate)
+    }
+
+    fn aggregate_cells<Q: ?Sized, R>(
+        &self,
+        start: *const Cell<K, V>,
+        last_cell: *const Cell<K, V>,
+        range: &R,
+    ) -> M::Summary
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
+    {
+        let mut aggregate = M::identity();
+
+        for mut cell_guard in CellIterator::new(start, last_cell) {
+            if cell_guard.is_empty() {
+                continue;
+            }
+
+            let lifted = cell_guard.read(|entry| {
+                let (key, value) = entry?;
+                let past_end = match range.end_bound() {
+                    Bound::Included(end) => {
+                        self.comparator.compare(key.borrow(), end) == Ordering::Greater
                     }
-                }
+                    Bound::Excluded(end) => {
+                        self.comparator.compare(key.borrow(), end) != Ordering::Less
+                    }
+                    Bound::Unbounded => false,
+                };
+                let before_start = match range.start_bound() {
+                    Bound::Included(start) => {
+                        self.comparator.compare(key.borrow(), start) == Ordering::Less
+                    }
+                    Bound::Excluded(start) => {
+                        self.comparator.compare(key.borrow(), start) != Ordering::Greater
+                    }
+                    Bound::Unbounded => false,
+                };
+                Some((past_end, (!before_start).then(|| M::lift(key, value))))
+            });
 
-                None
+            match lifted.unwrap() {
+                Some((true, _)) => break,
+                Some((false, Some(lifted))) => aggregate = M::combine(&aggregate, &lifted),
+                _ => (),
+            }
+        }
+
+        aggregate
+    }
+
+    pub fn first_cell<'a>(&self) -> Option<CellGuard<'a, K, V>> {
+        self.select(0)
+    }
+
+    // Address of the first cell holding a key greater than (or equal to, unless
+    // `skip_equal` is set) the search key.
+    pub fn position_after<Q: ?Sized>(&self, search_key: &Q, skip_equal: bool) -> *const Cell<K, V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let block_start = self
+            .block_start(search_key, true)
+            .unwrap_or(self.map.active_range.start);
+        let iter = CellIterator::new(block_start, self.map.active_range.end);
+
+        for mut cell_guard in iter {
+            if cell_guard.is_empty() {
+                continue;
+            }
+            let order = cell_guard
+                .read(|entry| {
+                    entry.map(|(key, _)| self.comparator.compare(key.borrow(), search_key))
+                })
+                .unwrap();
+            if order == Some(Ordering::Greater) || (!skip_equal && order == Some(Ordering::Equal)) {
+                return cell_guard.inner;
+            }
+        }
+
+        unsafe { self.map.active_range.end.add(1) }
+    }
+
+    // Guards from the last key before `search_key` through the first key after
+    // it, or through the first gap when none follows, as inserts take the
+    // first gap after the last smaller key. Nothing gets between them without
+    // one of the cells changing. `None` unless the guards show it missing.
+    pub fn gap_cells<'a, Q: ?Sized>(&self, search_key: &Q) -> Option<Vec<CellGuard<'a, K, V>>>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let next = self.position_after(search_key, false);
+        let mut first = next;
+        while first > self.map.active_range.start {
+            first = unsafe { first.sub(1) };
+            if !unsafe { CellGuard::from_raw(first) }.ok()?.is_empty() {
+                break;
+            }
+        }
+
+        let mut cells = Vec::new();
+        for cell_guard in CellIterator::new(first, self.map.active_range.end) {
+            let order = cell_guard
+                .read_with(|entry| {
+                    entry.map(|(key, _)| self.comparator.compare(key.borrow(), search_key))
+                })
+                .ok()?;
+            let last = match order {
+                None => next > self.map.active_range.end,
+                Some(Ordering::Less) if cells.is_empty() => false,
+                Some(Ordering::Greater) => true,
+                _ => return None,
+            };
+            cells.push(cell_guard);
+            if last {
+                break;
+            }
+        }
+
+        Some(cells)
+    }
+
+    pub fn find_cell<'a, Q: ?Sized>(
+        &self,
+        search_key: &Q,
+    ) -> Result<Option<CellGuard<'a, K, V>>, Error>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let block_start = match self.block_start(search_key, true) {
+            Some(block_start) => block_start,
+            None => return Ok(None),
+        };
+        let iter = CellIterator::new(block_start, self.map.active_range.end);
+
+        for mut cell_guard in iter {
+            let order = cell_guard.read(|entry| {
+                entry.map(|(key, _)| self.comparator.compare(key.borrow(), search_key))
+            })?;
+            match order {
+                Some(Ordering::Equal) => return Ok(Some(cell_guard)),
+                Some(Ordering::Greater) => return Ok(None),
+                _ => (),
             }
-            _ => unreachable!(),
         }
+
+        Ok(None)
     }
 }
 
-struct BlockSearchTree<K: Clone + Ord, V: Clone> {
-    nodes: Box<[UnsafeCell<Node<K, V>>]>,
+struct BlockSearchTree<K, V, M: Monoid<K, V>> {
+    nodes: Allocation<UnsafeCell<Node<K, V, M>>>,
+    // a power of two, one per block of the array
+    leaf_count: usize,
 }
 
-impl<'a, K, V> BlockSearchTree<K, V>
+// Room for an index's nodes before any are written
+type NodeSlot<K, V, M> = MaybeUninit<UnsafeCell<Node<K, V, M>>>;
+type NodeMemory<K, V, M> = Allocation<NodeSlot<K, V, M>>;
+// Where a subtree's root gets linked into its parent
+type ChildLink<K, V, M> = *mut NonNull<UnsafeCell<Node<K, V, M>>>;
+type LeafVisitor<'a, K, V, M> = dyn FnMut(&mut UnsafeCell<Node<K, V, M>>) + 'a;
+// Reads a block's aggregate from its cells
+type Recount<'a, K, V, M> = dyn Fn(&Block<K, V, M>) -> <M as Monoid<K, V>>::Summary + 'a;
+
+impl<'a, K, V, M> BlockSearchTree<K, V, M>
 where
-    K: Clone + Ord,
-    V: Clone,
+    M: Monoid<K, V>,
 {
-    fn new(cells: Arc<PackedMemoryArray<Cell<K, V>>>) -> BlockSearchTree<K, V> {
-        let mut nodes = Self::allocate(cells.len());
+    fn new(cells: Arc<PackedMemoryArray<Cell<K, V>>>) -> BlockSearchTree<K, V, M> {
+        let nodes = Self::allocate(cells.len(), cells.allocator());
+        Self::build(nodes, cells)
+    }
 
-        let mut leaves = Self::initialize_nodes(&mut *nodes, None);
-        let slot_size = f32::log2(cells.requested_capacity as f32) as usize; // https://github.com/rust-lang/rust/issues/70887
-        let mut slots = cells.as_slice().chunks_exact(slot_size);
+    // `nodes` must have come from `allocate` or `try_allocate` for these cells
+    fn build(
+        mut nodes: NodeMemory<K, V, M>,
+        cells: Arc<PackedMemoryArray<Cell<K, V>>>,
+    ) -> BlockSearchTree<K, V, M> {
+        // one block per leaf, spanning the whole active range whatever capacity was requested
+        let leaf_count = nodes.len().div_ceil(2);
+        let mut slots = cells.as_slice().chunks_exact(cells.block_len());
 
-        for leaf in leaves.iter_mut() {
+        Self::initialize_nodes(&mut nodes, None, &mut |leaf| {
             Self::finalize_leaf_node(leaf.get_mut(), slots.next().unwrap());
-        }
+        });
 
+        // every node was written by initialize_nodes
         let initialized_nodes = unsafe { nodes.assume_init() };
+        Self::finalize_internal_node(unsafe { &mut *initialized_nodes[0].get() });
 
         BlockSearchTree {
             nodes: initialized_nodes,
+            leaf_count,
         }
     }
 
-    fn allocate(leaf_count: usize) -> Box<[MaybeUninit<UnsafeCell<Node<K, V>>>]> {
-        let size = leaf_count;
-        // https://github.com/rust-lang/rust/issues/70887
-        let slot_size = f32::log2(size as f32) as usize;
+    fn allocate(leaf_count: usize, allocator: Arc<dyn Allocator>) -> NodeMemory<K, V, M> {
+        Allocation::uninit(Self::node_count(leaf_count), allocator)
+    }
+
+    fn try_allocate(
+        leaf_count: usize,
+        allocator: Arc<dyn Allocator>,
+    ) -> Result<NodeMemory<K, V, M>, Error> {
+        Allocation::try_uninit(Self::node_count(leaf_count), allocator)
+    }
+
+    fn node_count(size: usize) -> usize {
+        // rounding the slots up to a power of two keeps the tree complete
+        let slot_size = size.ilog2().next_power_of_two() as usize;
         let leaf_count = size / slot_size;
-        let node_count = 2 * leaf_count - 1;
-        // println!("tree has {:?} leaves, {:?} nodes", leaf_count, node_count);
-        Box::<[UnsafeCell<Node<K, V>>]>::new_uninit_slice(node_count as usize)
+        2 * leaf_count - 1
     }
 
-    fn initialize_nodes<'b>(
-        nodes: &'b mut [MaybeUninit<UnsafeCell<Node<K, V>>>],
-        parent_subtree: Option<*mut NonNull<UnsafeCell<Node<K, V>>>>,
-    ) -> Vec<&'b mut UnsafeCell<Node<K, V>>> {
+    // Hands each leaf to `on_leaf` from left to right as soon as it's linked
+    // in, so nothing is allocated to hold them
+    fn initialize_nodes(
+        nodes: &mut [NodeSlot<K, V, M>],
+        parent_subtree: Option<ChildLink<K, V, M>>,
+        on_leaf: &mut LeafVisitor<'_, K, V, M>,
+    ) {
         if nodes.len() <= 3 {
-            return Self::assign_node_values(nodes, parent_subtree);
+            return Self::assign_node_values(nodes, parent_subtree, on_leaf);
         }
 
         let (upper_mem, lower_mem) = Self::split_tree_memory(nodes);
         let num_lower_branches = upper_mem.len() + 1;
 
-        let leaves_of_upper = Self::initialize_nodes(upper_mem, parent_subtree);
-
         let nodes_per_branch = lower_mem.len() / num_lower_branches;
         let mut branches = lower_mem.chunks_exact_mut(nodes_per_branch);
 
-        leaves_of_upper
-            .into_iter()
-            .flat_map(|subtree_leaf| match unsafe { &mut *subtree_leaf.get() } {
+        Self::initialize_nodes(
+            upper_mem,
+            parent_subtree,
+            &mut |subtree_leaf| match subtree_leaf.get_mut() {
                 Node::Leaf(_, _) => unreachable!(),
                 Node::Internal { left, right, .. } => {
                     let lhs_mem = branches.next().unwrap();
                     let rhs_mem = branches.next().unwrap();
-                    let lhs = Self::initialize_nodes(lhs_mem, Some(left.as_mut_ptr()));
-                    let rhs = Self::initialize_nodes(rhs_mem, Some(right.as_mut_ptr()));
-                    lhs.into_iter().chain(rhs.into_iter())
+                    Self::initialize_nodes(lhs_mem, Some(left.as_mut_ptr()), on_leaf);
+                    Self::initialize_nodes(rhs_mem, Some(right.as_mut_ptr()), on_leaf);
                 }
-            })
-            .collect::<Vec<_>>()
+            },
+        );
     }
 
-    fn assign_node_values<'b>(
-        nodes: &'b mut [MaybeUninit<UnsafeCell<Node<K, V>>>],
-        parent_subtree: Option<*mut NonNull<UnsafeCell<Node<K, V>>>>,
-    ) -> Vec<&'b mut UnsafeCell<Node<K, V>>> {
+    fn assign_node_values(
+        nodes: &mut [NodeSlot<K, V, M>],
+        parent_subtree: Option<ChildLink<K, V, M>>,
+        on_leaf: &mut LeafVisitor<'_, K, V, M>,
+    ) {
         let num_nodes = nodes.len();
         assert!(num_nodes <= 3);
 
-        for i in 0..num_nodes as usize {
-            nodes[i].write(UnsafeCell::new(Node::Internal {
+        for node in nodes.iter_mut() {
+            node.write(UnsafeCell::new(Node::Internal {
                 min_rhs: Key::Supremum,
+                count: 0,
+                aggregate: M::identity(),
                 left: MaybeUninit::uninit(), // Todo: NonNull<MaybeUninit<T>>
                 right: MaybeUninit::uninit(),
             }));
         }
 
         if num_nodes == 1 {
-            let node = unsafe { nodes[0].assume_init_mut() };
-            return vec![node];
+            return on_leaf(unsafe { nodes[0].assume_init_mut() });
         }
 
         let left_node =
@@ -505,6 +2381,8 @@ where
 
         nodes[0].write(UnsafeCell::new(Node::Internal {
             min_rhs: Key::Supremum,
+            count: 0,
+            aggregate: M::identity(),
             left: MaybeUninit::new(left_node),
             right: MaybeUninit::new(right_node),
         }));
@@ -519,40 +2397,51 @@ where
             }
         }
 
-        let left_branch = unsafe { lhs[1].assume_init_mut() };
-        let right_branch = unsafe { rhs[0].assume_init_mut() };
-
-        return vec![left_branch, right_branch];
+        on_leaf(unsafe { lhs[1].assume_init_mut() });
+        on_leaf(unsafe { rhs[0].assume_init_mut() });
     }
 
-    fn split_tree_memory<'b>(
-        nodes: &'b mut [MaybeUninit<UnsafeCell<Node<K, V>>>],
-    ) -> (
-        &'b mut [MaybeUninit<UnsafeCell<Node<K, V>>>],
-        &'b mut [MaybeUninit<UnsafeCell<Node<K, V>>>],
-    ) {
-        let height = f32::log2(nodes.len() as f32 + 1f32);
-        let lower_height = ((height / 2f32).ceil() as u32).next_power_of_two();
-        let upper_height = height as u32 - lower_height;
+    #[allow(clippy::type_complexity)]
+    fn split_tree_memory(
+        nodes: &mut [NodeSlot<K, V, M>],
+    ) -> (&mut [NodeSlot<K, V, M>], &mut [NodeSlot<K, V, M>]) {
+        // the tree is complete, so its height is exact
+        let height = (nodes.len() + 1).ilog2();
+        let lower_height = height.div_ceil(2).next_power_of_two();
+        let upper_height = height - lower_height;
 
         let upper_subtree_length = 2 << (upper_height - 1);
         nodes.split_at_mut(upper_subtree_length - 1)
     }
 
-    fn finalize_leaf_node<'b>(leaf: &'b mut Node<K, V>, leaf_mem: &'b [Cell<K, V>]) -> () {
+    fn finalize_leaf_node<'b>(leaf: &'b mut Node<K, V, M>, leaf_mem: &'b [Cell<K, V>]) {
         match leaf {
             Node::Internal { .. } => {
                 let min_key = leaf_mem
-                    .first()
-                    .and_then(|c| unsafe { (*c.key.get()).as_ref() })
-                    .and_then(|k| Some(Key::Value(k.clone())))
+                    .iter()
+                    .find(|c| c.is_filled())
+                    .map(|c| {
+                        Key::Value((
+                            c as *const Cell<K, V>,
+                            c.version.load(AtomicOrdering::SeqCst),
+                        ))
+                    })
                     .unwrap_or(Key::Supremum);
 
                 let length = leaf_mem.len();
+                let count = leaf_mem.iter().filter(|c| c.is_filled()).count();
+                let aggregate = leaf_mem
+                    .iter()
+                    .filter_map(|c| unsafe { c.entry() })
+                    .fold(M::identity(), |acc, (key, value)| {
+                        M::combine(&acc, &M::lift(key, value))
+                    });
                 let ptr = leaf_mem as *const [Cell<K, V>] as *const Cell<K, V>;
                 let block = Block {
                     cell_slice_ptr: ptr,
                     length,
+                    count,
+                    aggregate,
                 };
                 *leaf = Node::Leaf(min_key, block);
             }
@@ -560,58 +2449,238 @@ where
         };
     }
 
-    fn root(&'a self) -> &Node<K, V> {
+    // Returns the smallest key in the subtree, recording the smallest key of
+    // each right-hand branch so searches can be routed towards it
+    fn finalize_internal_node(node: &mut Node<K, V, M>) -> MinKey<K, V> {
+        match node {
+            Node::Leaf(min_key, _) => *min_key,
+            Node::Internal {
+                min_rhs,
+                count,
+                aggregate,
+                left,
+                right,
+            } => {
+                let lhs = unsafe { &mut *left.assume_init_ref().as_ref().get() };
+                let rhs = unsafe { &mut *right.assume_init_ref().as_ref().get() };
+                let lhs_min = Self::finalize_internal_node(lhs);
+                *min_rhs = Self::finalize_internal_node(rhs);
+                *count = lhs.count() + rhs.count();
+                *aggregate = M::combine(lhs.aggregate(), rhs.aggregate());
+                // cells are in key order, so the left side's keys all come first
+                if lhs_min.is_supremum() {
+                    *min_rhs
+                } else {
+                    lhs_min
+                }
+            }
+        }
+    }
+
+    fn root(&'a self) -> &'a Node<K, V, M> {
         unsafe { &*self.nodes[0].get() }
     }
 
-    fn find<Q>(&'a self, search_key: &Q, for_insertion: bool) -> SearchResult<'a, K, V>
+    // The nth leaf's block, counting from the left
+    fn leaf(&'a self, n: usize) -> &'a Block<K, V, M> {
+        let mut node = self.root();
+        let mut first_leaf = 0;
+        let mut width = self.leaf_count;
+
+        loop {
+            match node {
+                Node::Leaf(_, block) => return block,
+                Node::Internal { left, right, .. } => {
+                    width /= 2;
+                    let child = if n < first_leaf + width {
+                        left
+                    } else {
+                        first_leaf += width;
+                        right
+                    };
+                    node = unsafe { &*child.assume_init_ref().as_ref().get() };
+                }
+            }
+        }
+    }
+
+    fn rank<Q: ?Sized, C>(&'a self, search_key: &Q, comparator: &C) -> (&'a Block<K, V, M>, usize)
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        self.root()
+            .search_with_rank(Key::Value(search_key), comparator)
+    }
+
+    // Finds the block holding the nth key, along with its position within the
+    // block, counting the `stale` leaves' changes into every subtree above them
+    fn select(&'a self, mut n: usize, stale: &[(usize, isize)]) -> (&'a Block<K, V, M>, usize) {
+        let mut node = self.root();
+        let mut first_leaf = 0;
+        let mut width = self.leaf_count;
+
+        loop {
+            match node {
+                Node::Leaf(_, block) => return (block, n),
+                Node::Internal { left, right, .. } => {
+                    width /= 2;
+                    let lhs = unsafe { &*left.assume_init_ref().as_ref().get() };
+                    let rhs = unsafe { &*right.assume_init_ref().as_ref().get() };
+
+                    let from = stale.partition_point(|&(leaf, _)| leaf < first_leaf);
+                    let to = stale.partition_point(|&(leaf, _)| leaf < first_leaf + width);
+                    let change: isize = stale[from..to].iter().map(|&(_, change)| change).sum();
+                    let lhs_count = (lhs.count() as isize + change) as usize;
+
+                    if n < lhs_count {
+                        node = lhs;
+                    } else {
+                        n -= lhs_count;
+                        first_leaf += width;
+                        node = rhs;
+                    }
+                }
+            }
+        }
+    }
+
+    // The block a key routes to, empty or not
+    fn block_for<Q: ?Sized, C>(&'a self, key: Key<&Q>, comparator: &C) -> &'a Block<K, V, M>
+    where
+        K: Borrow<Q>,
+        C: Comparator<Q>,
+    {
+        match self.root().search_to_block(key, true, comparator) {
+            SearchResult::Block(block) => block,
+            _ => unreachable!(),
+        }
+    }
+
+    // Combines the aggregates of the leaves in `leaves`, taking the cached
+    // aggregate of every subtree without `stale` leaves and recounting those
+    fn aggregate_leaves(
+        &'a self,
+        leaves: Range<usize>,
+        stale: &[usize],
+        recount: &Recount<'_, K, V, M>,
+    ) -> M::Summary {
+        Self::aggregate_subtree(self.root(), 0..self.leaf_count, &leaves, stale, recount)
+    }
+
+    fn aggregate_subtree(
+        node: &Node<K, V, M>,
+        span: Range<usize>,
+        leaves: &Range<usize>,
+        stale: &[usize],
+        recount: &Recount<'_, K, V, M>,
+    ) -> M::Summary {
+        if span.end <= leaves.start || leaves.end <= span.start {
+            return M::identity();
+        }
+        let from = stale.partition_point(|&leaf| leaf < span.start);
+        let to = stale.partition_point(|&leaf| leaf < span.end);
+        if from == to && leaves.start <= span.start && span.end <= leaves.end {
+            return node.aggregate().clone();
+        }
+
+        match node {
+            Node::Leaf(_, block) => recount(block),
+            Node::Internal { left, right, .. } => {
+                let lhs = unsafe { &*left.assume_init_ref().as_ref().get() };
+                let rhs = unsafe { &*right.assume_init_ref().as_ref().get() };
+                let middle = span.start + (span.end - span.start) / 2;
+                M::combine(
+                    &Self::aggregate_subtree(lhs, span.start..middle, leaves, stale, recount),
+                    &Self::aggregate_subtree(rhs, middle..span.end, leaves, stale, recount),
+                )
+            }
+        }
+    }
+
+    fn find<Q: ?Sized, C>(
+        &'a self,
+        search_key: &Q,
+        for_insertion: bool,
+        comparator: &C,
+    ) -> SearchResult<'a, K, V, M>
     where
         K: Borrow<Q>,
-        Q: Ord,
+        C: Comparator<Q>,
     {
         self.root()
-            .search_to_block(Key::Value(search_key), for_insertion)
+            .search_to_block(Key::Value(search_key), for_insertion, comparator)
     }
 }
 
-impl<K, V> Debug for BlockSearchTree<K, V>
+impl<K, V, M> Debug for BlockSearchTree<K, V, M>
 where
-    K: Ord + Clone + Debug,
-    V: Clone + Debug,
+    M: Monoid<K, V>,
+    K: Debug,
+    V: Debug,
 {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         formatter
             .debug_struct("BlockSearchTree")
-            .field("nodes", &format_args!("{:?}", self.nodes))
+            .field("nodes", &format_args!("{:?}", &*self.nodes))
             .finish()
     }
 }
 
-enum Node<K: Clone + Ord, V: Clone> {
-    Leaf(Key<K>, Block<K, V>),
+// Nodes don't keep a copy of their smallest key, only the cell it was in and
+// that cell's version when the index was built
+type MinKey<K, V> = Key<(*const Cell<K, V>, u16)>;
+
+// Runs `f` on a node's min key. A cell written since the index was built, or
+// being written now, no longer holds it and reads as the supremum, so searches
+// go left and scan forward into the cells after it.
+fn with_min_key<K, V, R>(min_key: &MinKey<K, V>, mut f: impl FnMut(Key<&K>) -> R) -> R {
+    let (cell, version) = match *min_key {
+        Key::Value(min_key) => min_key,
+        Key::Supremum => return f(Key::Supremum),
+    };
+
+    let cell_guard = unsafe { CellGuard::from_raw(cell) }.unwrap();
+    if cell_guard.cache_version == version {
+        let read = cell_guard.read_with(|entry| entry.map(|(key, _)| f(Key::Value(key))));
+        if let Ok(Some(result)) = read {
+            return result;
+        }
+    }
+    f(Key::Supremum)
+}
+
+enum Node<K, V, M: Monoid<K, V>> {
+    Leaf(MinKey<K, V>, Block<K, V, M>),
     Internal {
-        min_rhs: Key<K>,
-        left: MaybeUninit<NonNull<UnsafeCell<Node<K, V>>>>,
-        right: MaybeUninit<NonNull<UnsafeCell<Node<K, V>>>>,
+        min_rhs: MinKey<K, V>,
+        count: usize,
+        aggregate: M::Summary,
+        left: MaybeUninit<NonNull<UnsafeCell<Node<K, V, M>>>>,
+        right: MaybeUninit<NonNull<UnsafeCell<Node<K, V, M>>>>,
     },
 }
 
-impl<K, V> Node<K, V>
+impl<K, V, M> Node<K, V, M>
 where
-    K: Clone + Ord,
-    V: Clone,
+    M: Monoid<K, V>,
 {
-    fn search<'a, Q>(&'a self, key: Key<&Q>, allow_empty: bool) -> SearchResult<'a, K, V>
+    fn search<'a, Q: ?Sized, C>(
+        &'a self,
+        key: Key<&Q>,
+        allow_empty: bool,
+        comparator: &C,
+    ) -> SearchResult<'a, K, V, M>
     where
-        Q: Ord,
+        C: Comparator<Q>,
         K: Borrow<Q>,
     {
         match self {
             Node::Leaf(key_lock, block) => {
-                if !allow_empty && *key_lock == Key::Supremum {
+                if !allow_empty && key_lock.is_supremum() {
                     SearchResult::NotFound
                 } else {
-                    SearchResult::Block(block, Key::from(key_lock))
+                    SearchResult::Block(block)
                 }
             }
             Node::Internal {
@@ -620,30 +2689,96 @@ where
                 right,
                 ..
             } => {
-                let node = if min_rhs.is_supremum()
-                    || key < Key::Value(min_rhs.clone().unwrap().borrow())
-                {
-                    unsafe { &*left.assume_init_ref() }
+                let node = if Self::routes_left(min_rhs, key, comparator) {
+                    unsafe { left.assume_init_ref() }
                 } else {
-                    unsafe { &*right.assume_init_ref() }
+                    unsafe { right.assume_init_ref() }
                 };
                 SearchResult::Internal(unsafe { &*node.as_ref().get() })
             }
         }
     }
 
-    fn search_to_block<'a, Q>(&'a self, key: Key<&Q>, allow_empty: bool) -> SearchResult<'a, K, V>
+    fn count(&self) -> usize {
+        match self {
+            Node::Leaf(_, block) => block.count,
+            Node::Internal { count, .. } => *count,
+        }
+    }
+
+    fn aggregate(&self) -> &M::Summary {
+        match self {
+            Node::Leaf(_, block) => &block.aggregate,
+            Node::Internal { aggregate, .. } => aggregate,
+        }
+    }
+
+    fn routes_left<Q: ?Sized, C>(min_rhs: &MinKey<K, V>, key: Key<&Q>, comparator: &C) -> bool
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        with_min_key(min_rhs, |min_rhs| match min_rhs {
+            Key::Value(min_rhs) => {
+                key.compare(&Key::Value(min_rhs.borrow()), comparator) == Ordering::Less
+            }
+            min_rhs => min_rhs.is_supremum(),
+        })
+    }
+
+    // Finds the block a key belongs in, along with the number of keys held by
+    // the blocks before it
+    fn search_with_rank<Q: ?Sized, C>(
+        &self,
+        key: Key<&Q>,
+        comparator: &C,
+    ) -> (&Block<K, V, M>, usize)
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let mut node = self;
+        let mut preceding = 0;
+
+        loop {
+            match node {
+                Node::Leaf(_, block) => return (block, preceding),
+                Node::Internal {
+                    min_rhs,
+                    left,
+                    right,
+                    ..
+                } => {
+                    let lhs = unsafe { &*left.assume_init_ref().as_ref().get() };
+                    let rhs = unsafe { &*right.assume_init_ref().as_ref().get() };
+                    if Self::routes_left(min_rhs, key, comparator) {
+                        node = lhs;
+                    } else {
+                        preceding += lhs.count();
+                        node = rhs;
+                    }
+                }
+            }
+        }
+    }
+
+    fn search_to_block<'a, Q: ?Sized, C>(
+        &'a self,
+        key: Key<&Q>,
+        allow_empty: bool,
+        comparator: &C,
+    ) -> SearchResult<'a, K, V, M>
     where
-        Q: Ord,
+        C: Comparator<Q>,
         K: Borrow<Q>,
     {
         let mut result = None;
         let mut node = self;
 
-        while let None = result {
-            match node.search(key, allow_empty) {
+        while result.is_none() {
+            match node.search(key, allow_empty, comparator) {
                 SearchResult::Internal(next_node) => node = next_node,
-                x @ _ => result = Some(x),
+                x => result = Some(x),
             }
         }
 
@@ -651,34 +2786,41 @@ where
     }
 }
 
-impl<K, V> Debug for Node<K, V>
+impl<K, V, M> Debug for Node<K, V, M>
 where
-    K: Ord + Clone + Debug,
-    V: Clone + Debug,
+    M: Monoid<K, V>,
+    K: Debug,
+    V: Debug,
 {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Node::Leaf(key, ..) => formatter
-                .debug_struct("Node::Leaf")
-                .field("key", &format_args!("{:?}", key))
-                .finish(),
-            Node::Internal { min_rhs, .. } => formatter
-                .debug_struct("Node::Internal")
-                .field("min_rhs", &format_args!("{:?}", min_rhs))
-                .finish(),
+            Node::Leaf(key, ..) => with_min_key(key, |key| {
+                formatter
+                    .debug_struct("Node::Leaf")
+                    .field("key", &key)
+                    .finish()
+            }),
+            Node::Internal { min_rhs, .. } => with_min_key(min_rhs, |min_rhs| {
+                formatter
+                    .debug_struct("Node::Internal")
+                    .field("min_rhs", &min_rhs)
+                    .finish()
+            }),
         }
     }
 }
 
-enum SearchResult<'a, K: Clone + Ord, V: Clone> {
-    Block(&'a Block<K, V>, Key<&'a K>),
-    Internal(&'a Node<K, V>),
+enum SearchResult<'a, K, V, M: Monoid<K, V>> {
+    Block(&'a Block<K, V, M>),
+    Internal(&'a Node<K, V, M>),
     NotFound,
 }
 
-struct Block<K: Clone, V: Clone> {
+struct Block<K, V, M: Monoid<K, V>> {
     cell_slice_ptr: *const Cell<K, V>,
     length: usize,
+    count: usize,
+    aggregate: M::Summary,
 }
 
-unsafe impl<K: Clone, V: Clone> Send for Block<K, V> {}
+unsafe impl<K, V, M: Monoid<K, V>> Send for Block<K, V, M> {}
@@ -0,0 +1,174 @@
+use core::borrow::Borrow;
+use core::cmp::Ordering;
+use core::fmt::{self, Debug};
+use core::iter::Peekable;
+use core::ops::RangeBounds;
+
+use super::btree_map::{self, BTreeMap};
+
+// The map's cells keep values uninitialized while empty, so `()` takes no
+// room in them and each cell holds just the key and its marker
+pub struct BTreeSet<T: 'static + Ord> {
+    map: BTreeMap<T, ()>,
+}
+
+impl<T> BTreeSet<T>
+where
+    T: 'static + Ord,
+{
+    pub fn new(capacity: u32) -> BTreeSet<T> {
+        BTreeSet {
+            map: BTreeMap::new(capacity),
+        }
+    }
+
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where
+        Q: ?Sized + Ord,
+        T: Borrow<Q>,
+    {
+        self.map.get(value).is_some()
+    }
+
+    // Returns false if the value was already present
+    pub fn insert(&mut self, value: T) -> bool {
+        if self.contains(&value) {
+            return false;
+        }
+
+        self.map.insert(value, ());
+        true
+    }
+
+    pub fn remove<Q>(&mut self, value: &Q) -> bool
+    where
+        Q: ?Sized + Ord,
+        T: Borrow<Q>,
+    {
+        self.map.remove(value).is_some()
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            inner: self.map.iter(),
+        }
+    }
+
+    pub fn range<Q, R>(&self, range: R) -> Iter<'_, T>
+    where
+        Q: ?Sized + Ord,
+        T: Borrow<Q>,
+        R: RangeBounds<Q>,
+    {
+        Iter {
+            inner: self.map.range(range),
+        }
+    }
+
+    pub fn union<'a>(&'a self, other: &'a BTreeSet<T>) -> Union<'a, T> {
+        Union(MergeIter::new(self.iter(), other.iter()))
+    }
+
+    pub fn intersection<'a>(&'a self, other: &'a BTreeSet<T>) -> Intersection<'a, T> {
+        Intersection(MergeIter::new(self.iter(), other.iter()))
+    }
+
+    pub fn difference<'a>(&'a self, other: &'a BTreeSet<T>) -> Difference<'a, T> {
+        Difference(MergeIter::new(self.iter(), other.iter()))
+    }
+}
+
+impl<T> Debug for BTreeSet<T>
+where
+    T: 'static + Ord + Debug,
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.debug_set().entries(self.iter()).finish()
+    }
+}
+
+pub struct Iter<'a, T> {
+    inner: btree_map::Iter<'a, T, ()>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(value, _)| value)
+    }
+}
+
+// Walks both sets in order at once, pairing up equal values
+struct MergeIter<'a, T: Ord> {
+    lhs: Peekable<Iter<'a, T>>,
+    rhs: Peekable<Iter<'a, T>>,
+}
+
+impl<'a, T: Ord> MergeIter<'a, T> {
+    fn new(lhs: Iter<'a, T>, rhs: Iter<'a, T>) -> MergeIter<'a, T> {
+        MergeIter {
+            lhs: lhs.peekable(),
+            rhs: rhs.peekable(),
+        }
+    }
+
+    fn next(&mut self) -> Option<(Option<&'a T>, Option<&'a T>)> {
+        let order = match (self.lhs.peek(), self.rhs.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(lhs), Some(rhs)) => lhs.cmp(rhs),
+        };
+
+        match order {
+            Ordering::Less => Some((self.lhs.next(), None)),
+            Ordering::Greater => Some((None, self.rhs.next())),
+            Ordering::Equal => Some((self.lhs.next(), self.rhs.next())),
+        }
+    }
+}
+
+pub struct Union<'a, T: Ord>(MergeIter<'a, T>);
+
+impl<'a, T: Ord> Iterator for Union<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(lhs, rhs)| lhs.or(rhs).unwrap())
+    }
+}
+
+pub struct Intersection<'a, T: Ord>(MergeIter<'a, T>);
+
+impl<'a, T: Ord> Iterator for Intersection<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.0.next()? {
+                (Some(value), Some(_)) => return Some(value),
+                // one side has run out, nothing else can match
+                (None, Some(_)) if self.0.lhs.peek().is_none() => return None,
+                (Some(_), None) if self.0.rhs.peek().is_none() => return None,
+                _ => continue,
+            }
+        }
+    }
+}
+
+pub struct Difference<'a, T: Ord>(MergeIter<'a, T>);
+
+impl<'a, T: Ord> Iterator for Difference<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.0.next()? {
+                (Some(value), None) => return Some(value),
+                (None, Some(_)) if self.0.lhs.peek().is_none() => return None,
+                _ => continue,
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt::{self, Debug};
+use core::mem;
+use core::ops::Bound;
+use core::slice;
+
+use super::btree_map::{self, BTreeMap};
+use super::error::Error;
+
+// Keys per block before it's split in two
+const BLOCK_ENTRIES: usize = 32;
+
+// A map from byte strings that stores runs of keys front-coded. Keys are
+// grouped into blocks of up to BLOCK_ENTRIES, each holding its first key in
+// full and every other key as the length it shares with that first key plus
+// the remaining suffix. The blocks are the values of an ordinary map, filed
+// under the shortest prefix that separates them from the block before, so the
+// index above them only ever sees those separators.
+//
+// Each block sits on the heap behind the one cell it's filed in, so while the
+// front-coding saves memory, the keys don't live in the array's cells and a
+// lookup leaves the array to scan them.
+pub struct BytesMap<V: 'static> {
+    // the lowest block is always filed under the empty separator
+    blocks: BTreeMap<Vec<u8>, Block<V>>,
+    len: usize,
+}
+
+impl<V: 'static> BytesMap<V> {
+    // `capacity` only sizes the map to start with, it grows as blocks split
+    pub fn new(capacity: u32) -> BytesMap<V> {
+        // blocks are half full after a split
+        let mut blocks = BTreeMap::new(capacity / (BLOCK_ENTRIES as u32 / 2) + 1);
+        blocks.insert(Vec::new(), Block::default());
+        BytesMap { blocks, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        let (_, block) = self
+            .blocks
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()?;
+        let position = block.locate(key).0.ok()?;
+        Some(&block.values[position])
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
+        self.try_insert(key, value).unwrap()
+    }
+
+    // A failed insert leaves the map as it was
+    pub fn try_insert(&mut self, key: &[u8], value: V) -> Result<Option<V>, Error> {
+        let (lower, block) = self.block_mut(key);
+        let (position, offset) = match block.locate(key) {
+            (Ok(position), _) => return Ok(Some(mem::replace(&mut block.values[position], value))),
+            (Err(position), offset) => (position, offset),
+        };
+        block.insert(position, offset, key, value);
+
+        if block.values.len() > BLOCK_ENTRIES {
+            let middle = block.values.len() / 2;
+            let separator = separator(&block.key(middle - 1), &block.key(middle)).to_vec();
+
+            // the new block is filed empty first, so a map that can't grow
+            // loses nothing but the entry just added
+            if let Err(error) = self.blocks.try_insert(separator.clone(), Block::default()) {
+                let block = self.blocks.get_mut(lower.as_slice()).unwrap();
+                drop(block.remove(position, offset));
+                return Err(error);
+            }
+            let upper = self
+                .blocks
+                .get_mut(lower.as_slice())
+                .unwrap()
+                .split_off(middle);
+            *self.blocks.get_mut(separator.as_slice()).unwrap() = upper;
+        }
+
+        self.len += 1;
+        Ok(None)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let (separator, block) = self.block_mut(key);
+        let (position, offset) = match block.locate(key) {
+            (Ok(position), offset) => (position, offset),
+            (Err(_), _) => return None,
+        };
+        let value = block.remove(position, offset);
+
+        // the keys it covered fall to the block before
+        if block.values.is_empty() && !separator.is_empty() {
+            self.blocks.remove(&separator);
+        }
+
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter {
+            blocks: self.blocks.iter(),
+            block: None,
+        }
+    }
+
+    // The block whose separator is the last one at or before `key`, found
+    // without lending out every block before it the way `range_mut` would
+    fn block_mut(&mut self, key: &[u8]) -> (Vec<u8>, &mut Block<V>) {
+        let (separator, _) = self
+            .blocks
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .unwrap();
+        let separator = separator.clone();
+        let block = self.blocks.get_mut(separator.as_slice()).unwrap();
+        (separator, block)
+    }
+}
+
+impl<V: 'static + Debug> Debug for BytesMap<V> {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// The shortest prefix of `upper` that still sorts after `lower`
+fn separator<'a>(lower: &[u8], upper: &'a [u8]) -> &'a [u8] {
+    let shared = shared_prefix_len(lower, upper);
+    &upper[..shared + 1]
+}
+
+fn shared_prefix_len(lhs: &[u8], rhs: &[u8]) -> usize {
+    lhs.iter().zip(rhs).take_while(|(l, r)| l == r).count()
+}
+
+#[derive(Clone)]
+struct Block<V> {
+    first: Vec<u8>,
+    // every key after the first as the LEB128 length it shares with `first`,
+    // the LEB128 length of the rest, then the rest itself
+    rest: Vec<u8>,
+    values: Vec<V>,
+}
+
+impl<V> Default for Block<V> {
+    fn default() -> Self {
+        Block {
+            first: Vec::new(),
+            rest: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+}
+
+impl<V> Block<V> {
+    // Where `key` is or would go, and the offset into `rest` its entry starts
+    // at. Keys are compared a piece at a time, without being put back together.
+    fn locate(&self, key: &[u8]) -> (Result<usize, usize>, usize) {
+        let mut keys = self.keys();
+        let mut position = 0;
+        loop {
+            let offset = keys.offset();
+            let (prefix, suffix) = match keys.next() {
+                Some(pieces) => pieces,
+                None => return (Err(position), offset),
+            };
+            match prefix.iter().chain(suffix).cmp(key.iter()) {
+                Ordering::Less => position += 1,
+                Ordering::Equal => return (Ok(position), offset),
+                Ordering::Greater => return (Err(position), offset),
+            }
+        }
+    }
+
+    // Only the new entry is encoded and spliced in, unless it becomes the
+    // first key and the others have to be coded against it instead
+    fn insert(&mut self, position: usize, offset: usize, key: &[u8], value: V) {
+        if position == 0 {
+            self.rest = encode(key, self.keys());
+            self.first = key.to_vec();
+        } else {
+            let mut entry = Vec::new();
+            encode_entry(&mut entry, &self.first, (key, &[]));
+            self.rest.splice(offset..offset, entry);
+        }
+        self.values.insert(position, value);
+    }
+
+    fn remove(&mut self, position: usize, offset: usize) -> V {
+        if position == 0 {
+            let mut keys = self.keys().skip(1);
+            let (first, rest) = match keys.next() {
+                Some((prefix, suffix)) => {
+                    let first = [prefix, suffix].concat();
+                    let rest = encode(&first, keys);
+                    (first, rest)
+                }
+                None => (Vec::new(), Vec::new()),
+            };
+            self.first = first;
+            self.rest = rest;
+        } else {
+            let mut entry = &self.rest[offset..];
+            read_len(&mut entry);
+            let suffix_len = read_len(&mut entry);
+            let end = self.rest.len() - entry.len() + suffix_len;
+            self.rest.drain(offset..end);
+        }
+        self.values.remove(position)
+    }
+
+    // Moves the keys from `position` on into a block of their own
+    fn split_off(&mut self, position: usize) -> Block<V> {
+        let mut keys = self.keys();
+        keys.by_ref().take(position).for_each(drop);
+        let offset = keys.offset();
+        let (prefix, suffix) = keys.next().unwrap();
+        let first = [prefix, suffix].concat();
+        let rest = encode(&first, keys);
+
+        self.rest.truncate(offset);
+        Block {
+            first,
+            rest,
+            values: self.values.split_off(position),
+        }
+    }
+
+    fn key(&self, position: usize) -> Vec<u8> {
+        let (prefix, suffix) = self.keys().nth(position).unwrap();
+        [prefix, suffix].concat()
+    }
+
+    fn keys(&self) -> Keys<'_> {
+        Keys {
+            first: &self.first,
+            rest: &self.rest,
+            len: self.rest.len(),
+            remaining: self.values.len(),
+            started: false,
+        }
+    }
+}
+
+// Codes each key against `first`
+fn encode<'a>(first: &[u8], keys: impl Iterator<Item = (&'a [u8], &'a [u8])>) -> Vec<u8> {
+    let mut rest = Vec::new();
+    for key in keys {
+        encode_entry(&mut rest, first, key);
+    }
+    rest
+}
+
+fn encode_entry(bytes: &mut Vec<u8>, first: &[u8], (prefix, suffix): (&[u8], &[u8])) {
+    let key = || prefix.iter().chain(suffix);
+    let shared = first.iter().zip(key()).take_while(|(l, r)| l == r).count();
+    write_len(bytes, shared);
+    write_len(bytes, prefix.len() + suffix.len() - shared);
+    bytes.extend(key().skip(shared));
+}
+
+fn write_len(bytes: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        bytes.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    bytes.push(len as u8);
+}
+
+fn read_len(bytes: &mut &[u8]) -> usize {
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[0];
+        *bytes = &bytes[1..];
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte < 0x80 {
+            return len;
+        }
+        shift += 7;
+    }
+}
+
+// Each key of a block as the part it shares with the first key and the rest
+struct Keys<'a> {
+    first: &'a [u8],
+    rest: &'a [u8],
+    // of the whole of `rest`
+    len: usize,
+    remaining: usize,
+    started: bool,
+}
+
+impl Keys<'_> {
+    // Where the next key's entry starts in the block's `rest`
+    fn offset(&self) -> usize {
+        self.len - self.rest.len()
+    }
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        if !self.started {
+            self.started = true;
+            return Some((self.first, &[]));
+        }
+
+        let shared = read_len(&mut self.rest);
+        let suffix_len = read_len(&mut self.rest);
+        let (suffix, rest) = self.rest.split_at(suffix_len);
+        self.rest = rest;
+        Some((&self.first[..shared], suffix))
+    }
+}
+
+pub struct Iter<'a, V> {
+    blocks: btree_map::Iter<'a, Vec<u8>, Block<V>>,
+    block: Option<(Keys<'a>, slice::Iter<'a, V>)>,
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (Vec<u8>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((keys, values)) = self.block.as_mut() {
+                if let Some((prefix, suffix)) = keys.next() {
+                    return Some(([prefix, suffix].concat(), values.next().unwrap()));
+                }
+            }
+
+            let (_, block) = self.blocks.next()?;
+            self.block = Some((block.keys(), block.values.iter()));
+        }
+    }
+}
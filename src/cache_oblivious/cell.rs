@@ -1,202 +1,332 @@
 // use once_cell::sync::Lazy;
-use std::cell::{OnceCell, UnsafeCell};
-use std::cmp::{Ord, Ordering};
-use std::error::Error;
-use std::fmt::{self, Debug, Display};
-use std::marker::PhantomData;
-use std::sync::atomic::{AtomicPtr, AtomicU16, Ordering as AtomicOrdering};
-
-#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
-pub enum Key<T: Ord> {
-    Infimum,
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::cmp::{Ord, Ordering};
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU16, Ordering as AtomicOrdering};
+
+use super::comparator::{Comparator, OrdComparator};
+use super::error::Error;
+
+// How many times a cell caught mid-write is tried again before giving up
+pub const MAX_RETRIES: usize = 1 << 10;
+
+// Lets the writer in the way finish. It may have been preempted mid-write,
+// in which case spinning on a single core never sees it done.
+pub fn back_off() {
+    #[cfg(feature = "std")]
+    std::thread::yield_now();
+    #[cfg(not(feature = "std"))]
+    core::hint::spin_loop();
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Key<T> {
     Value(T),
     Supremum,
 }
 
-impl<'a, T: Ord> Key<T> {
+impl<T> Key<T> {
     pub fn as_ref(&self) -> Key<&T> {
         match *self {
             Key::Value(ref v) => Key::Value(v),
-            Key::Infimum => Key::Infimum,
             Key::Supremum => Key::Supremum,
         }
     }
 
-    pub fn unwrap(self) -> T {
-        match self {
-            Key::Value(val) => val,
-            _ => panic!("Called Key::unwrap() on an infinite value"),
-        }
-    }
-
-    pub fn is_infimum(&self) -> bool {
-        match self {
-            Key::Infimum => true,
-            _ => false,
-        }
+    pub fn is_supremum(&self) -> bool {
+        matches!(self, Key::Supremum)
     }
+}
 
-    pub fn is_supremum(&self) -> bool {
-        match self {
-            Key::Supremum => true,
-            _ => false,
+impl<T: ?Sized> Key<&T> {
+    // The supremum sits above every value, values are ordered by `comparator`
+    pub fn compare<C: Comparator<T>>(&self, other: &Self, comparator: &C) -> Ordering {
+        match (self, other) {
+            (Key::Value(a), Key::Value(b)) => comparator.compare(a, b),
+            (Key::Supremum, Key::Supremum) => Ordering::Equal,
+            (_, Key::Supremum) => Ordering::Less,
+            (Key::Supremum, _) => Ordering::Greater,
         }
     }
 }
 
 impl<T: Ord> Ord for Key<T> {
     fn cmp(&self, other: &Self) -> Ordering {
-        match (self, other) {
-            (x, y) if x == y => Ordering::Equal,
-            (Key::Infimum, _) | (_, Key::Supremum) => Ordering::Less,
-            (Key::Supremum, _) | (_, Key::Infimum) => Ordering::Greater,
-            (Key::Value(a), Key::Value(b)) => a.cmp(b),
-        }
+        self.as_ref().compare(&other.as_ref(), &OrdComparator)
     }
 }
 
-impl<'a, T: Ord> From<&'a Key<T>> for Key<&'a T> {
+impl<T: Ord> PartialOrd for Key<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, T> From<&'a Key<T>> for Key<&'a T> {
     fn from(k: &'a Key<T>) -> Key<&'a T> {
         k.as_ref()
     }
 }
 
+// Markers only say what is happening to a cell. Keys and values are moved
+// straight into and out of the cell itself, so there is only ever one of each.
 #[derive(Debug, Copy, Clone)]
-pub enum Marker<K: Clone, V: Clone> {
+pub enum Marker {
     Empty(u16),
     Move(u16, isize),
-    InsertCell(u16, K, V),
-    DeleteCell(u16, K),
+    InsertCell(u16),
+    DeleteCell(u16),
 }
 
-impl<K: Clone, V: Clone> Marker<K, V> {
+// Every fresh cell shares this one, so filling an array allocates nothing per
+// cell. It lives in read-only memory and is never written through or freed.
+static FRESH_MARKER: Marker = Marker::Empty(1);
+
+impl Marker {
+    pub fn fresh() -> *mut Marker {
+        ptr::addr_of!(FRESH_MARKER) as *mut Marker
+    }
+
+    // Rewrites a marker no cell points at any more, boxing a new one in place
+    // of the shared fresh marker
+    pub unsafe fn reuse(ptr: *mut Marker, marker: Marker) -> *mut Marker {
+        if ptr == Self::fresh() {
+            Box::into_raw(Box::new(marker))
+        } else {
+            ptr.write(marker);
+            ptr
+        }
+    }
+
+    pub unsafe fn free(ptr: *mut Marker) {
+        if ptr != Self::fresh() {
+            drop(Box::from_raw(ptr));
+        }
+    }
+
     pub fn version(&self) -> &u16 {
         match self {
             Marker::Empty(v)
             | Marker::Move(v, _)
-            | Marker::InsertCell(v, _, _)
-            | Marker::DeleteCell(v, _) => v,
+            | Marker::InsertCell(v)
+            | Marker::DeleteCell(v) => v,
         }
     }
 }
 
-pub struct Cell<K: Clone, V: Clone> {
+// repr(C) so a file-backed array finds keys and values at the same offsets every run
+#[repr(C)]
+pub struct Cell<K, V> {
     pub version: AtomicU16,
-    pub marker: Option<AtomicPtr<Marker<K, V>>>,
-    pub key: UnsafeCell<Option<K>>,
-    pub value: UnsafeCell<Option<V>>,
+    // says whether the key and value are initialized, in a byte of its own so
+    // a file-backed array can check it before trusting either
+    pub filled: UnsafeCell<bool>,
+    pub marker: Option<AtomicPtr<Marker>>,
+    pub key: UnsafeCell<MaybeUninit<K>>,
+    // a set's `()` takes no room
+    pub value: UnsafeCell<MaybeUninit<V>>,
 }
 
-unsafe impl<K: Clone, V: Clone> Send for Cell<K, V> {}
-unsafe impl<K: Clone, V: Clone> Sync for Cell<K, V> {}
+unsafe impl<K, V> Send for Cell<K, V> {}
+unsafe impl<K, V> Sync for Cell<K, V> {}
 
-impl<K: Clone, V: Clone> Cell<K, V> {
-    pub fn new(marker_ptr: *mut Marker<K, V>) -> Cell<K, V> {
+impl<K, V> Cell<K, V> {
+    pub fn new(marker_ptr: *mut Marker) -> Cell<K, V> {
         Cell {
             version: AtomicU16::new(1),
+            filled: UnsafeCell::new(false),
             marker: Some(AtomicPtr::new(marker_ptr)),
-            key: UnsafeCell::new(None),
-            value: UnsafeCell::new(None),
+            key: UnsafeCell::new(MaybeUninit::uninit()),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn is_filled(&self) -> bool {
+        unsafe { *self.filled.get() }
+    }
+
+    pub unsafe fn key(&self) -> Option<&K> {
+        self.entry().map(|(key, _)| key)
+    }
+
+    // The entry held, if any. Nothing stops a writer replacing it meanwhile.
+    pub unsafe fn entry(&self) -> Option<(&K, &V)> {
+        if !self.is_filled() {
+            return None;
+        }
+        Some((
+            (*self.key.get()).assume_init_ref(),
+            (*self.value.get()).assume_init_ref(),
+        ))
+    }
+
+    // Only for the one writer the marker or index lock lets at the cell
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn entry_mut(&self) -> Option<(&K, &mut V)> {
+        if !self.is_filled() {
+            return None;
         }
+        Some((
+            (*self.key.get()).assume_init_ref(),
+            (*self.value.get()).assume_init_mut(),
+        ))
+    }
+
+    // Moves the entry out, leaving the cell empty
+    pub unsafe fn take(&self) -> Option<(K, V)> {
+        if !self.is_filled() {
+            return None;
+        }
+        *self.filled.get() = false;
+        Some((
+            (*self.key.get()).assume_init_read(),
+            (*self.value.get()).assume_init_read(),
+        ))
+    }
+
+    // Fills the cell, handing back the value it replaces
+    pub unsafe fn put(&self, key: K, value: V) -> Option<V> {
+        let previous = self.take().map(|(_, value)| value);
+        (*self.key.get()).write(key);
+        (*self.value.get()).write(value);
+        *self.filled.get() = true;
+        previous
+    }
+
+    // Moves the entry of `other` in bit for bit, leaving `other` empty.
+    // Whatever this cell held is overwritten, not dropped.
+    pub unsafe fn move_from(&self, other: &Cell<K, V>) {
+        self.key.get().write(other.key.get().read());
+        self.value.get().write(other.value.get().read());
+        *self.filled.get() = other.is_filled();
+        *other.filled.get() = false;
+    }
+
+    // Moves the version on after a write made while no other writer could
+    // reach the cell, so guards and index entries taken before it go stale
+    pub fn bump_version(&self) {
+        let marker = self.marker.as_ref().unwrap();
+        let next_version = self.version.load(AtomicOrdering::SeqCst).wrapping_add(1);
+        let next_marker = unsafe {
+            Marker::reuse(
+                marker.load(AtomicOrdering::SeqCst),
+                Marker::Empty(next_version),
+            )
+        };
+        marker.store(next_marker, AtomicOrdering::SeqCst);
+        self.version.store(next_version, AtomicOrdering::SeqCst);
     }
 }
 
-impl<K, V> Default for Cell<K, V>
-where
-    K: Clone,
-    V: Clone,
-{
+impl<K, V> Default for Cell<K, V> {
     fn default() -> Self {
-        let marker = Box::new(Marker::<K, V>::Empty(1));
-        let ptr = Box::into_raw(marker);
-        Cell::new(ptr)
+        Cell::new(Marker::fresh())
     }
 }
 
-impl<K: Clone, V: Clone> Drop for Cell<K, V> {
+impl<K, V> Drop for Cell<K, V> {
     fn drop(&mut self) {
+        // plain keys and values need no dropping, which also leaves a
+        // file-backed array's entries in the file
+        if mem::needs_drop::<K>() || mem::needs_drop::<V>() {
+            drop(unsafe { self.take() });
+        }
         let ptr = self.marker.take().unwrap();
-        let marker = ptr.load(AtomicOrdering::Acquire);
-        unsafe { Box::from_raw(marker) };
+        unsafe { Marker::free(ptr.load(AtomicOrdering::Acquire)) };
     }
 }
 
-impl<K: Debug + Clone, V: Debug + Clone> Debug for Cell<K, V> {
+impl<K: Debug, V: Debug> Debug for Cell<K, V> {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         let version = self.version.load(AtomicOrdering::Acquire);
         let marker = unsafe { &*self.marker.as_ref().unwrap().load(AtomicOrdering::Acquire) };
-        let key = unsafe { &*self.key.get() };
-        let value = unsafe { &*self.value.get() };
+        let entry = unsafe { self.entry() };
+        let key = entry.map(|(key, _)| key);
+        let value = entry.map(|(_, value)| value);
 
         let mut dbg_struct = formatter.debug_struct("Cell");
 
         dbg_struct
             .field("version", &version)
             .field("marker", marker)
-            .field("key", key)
-            .field("value", value);
+            .field("key", &key)
+            .field("value", &value);
 
         dbg_struct.finish()
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct CellData<K: Clone, V: Clone> {
-    pub key: K,
-    pub value: V,
-    pub marker: Marker<K, V>,
-}
-
-pub struct CellGuard<'a, K: 'a + Clone, V: 'a + Clone> {
+pub struct CellGuard<'a, K: 'a, V: 'a> {
     pub inner: &'a Cell<K, V>,
     pub cache_version: u16,
     pub is_filled: bool,
-    cache_data: OnceCell<Option<CellData<K, V>>>,
-    cache_marker_ptr: *mut Marker<K, V>,
+    cache_marker_ptr: *mut Marker,
     _phantom: PhantomData<&'a Cell<K, V>>,
 }
 
-impl<K: Clone, V: Clone> CellGuard<'_, K, V> {
+impl<'a, K, V> CellGuard<'a, K, V> {
     pub fn is_empty(&self) -> bool {
         !self.is_filled
     }
 
-    pub fn cache(&self) -> Result<&Option<CellData<K, V>>, CellReadError> {
-        self.cache_data.get_or_try_init(|| {
-            let version = self.inner.version.load(AtomicOrdering::SeqCst);
-            let key = unsafe { (*self.inner.key.get()).clone() };
-
-            let value = if key.is_some() {
-                unsafe { (*self.inner.value.get()).clone() }
-            } else {
-                None
-            };
-            let current_marker_raw = self
-                .inner
-                .marker
-                .as_ref()
-                .unwrap()
-                .load(AtomicOrdering::SeqCst);
-            let marker = unsafe { (*current_marker_raw).clone() };
+    // Runs `f` on the entry as it was when the guard was taken. The references
+    // can't be trusted once another write starts, so the result only comes
+    // back if none started or finished while `f` ran.
+    pub fn read_with<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(Option<(&K, &V)>) -> R,
+    {
+        if !self.is_current() {
+            return Err(Error::Contended);
+        }
 
-            if version != *marker.version() {
-                return Result::Err(CellReadError {});
-                // todo "Read marker, perform action there, reload data"
-            }
+        let entry = unsafe { self.inner.entry() };
+        let result = f(entry);
 
-            if key.is_some() {
-                Ok(Some(CellData {
-                    key: key.unwrap(),
-                    value: value.unwrap(),
-                    marker,
-                }))
-            } else {
-                Ok(None)
+        if !self.is_current() {
+            return Err(Error::Contended);
+        }
+        Ok(result)
+    }
+
+    // Waits out a write in flight, taking the guard again each time it catches one
+    pub fn read<R, F>(&mut self, mut f: F) -> Result<R, Error>
+    where
+        F: FnMut(Option<(&K, &V)>) -> R,
+    {
+        for _ in 0..MAX_RETRIES {
+            match self.read_with(&mut f) {
+                Err(Error::Contended) => {
+                    back_off();
+                    *self = unsafe { CellGuard::from_raw(self.inner) }?;
+                }
+                result => return result,
             }
-        })
+        }
+
+        Err(Error::Contended)
     }
 
-    pub fn update(&mut self, marker: Marker<K, V>) -> Result<*mut Marker<K, V>, Box<dyn Error>> {
+    // True if nobody has written to the cell since the guard was taken
+    pub fn is_current(&self) -> bool {
+        let marker_raw = self
+            .inner
+            .marker
+            .as_ref()
+            .unwrap()
+            .load(AtomicOrdering::SeqCst);
+        let version = self.inner.version.load(AtomicOrdering::SeqCst);
+
+        marker_raw == self.cache_marker_ptr
+            && version == self.cache_version
+            && unsafe { *(*marker_raw).version() } == version
+    }
+
+    pub fn update(&mut self, marker: Marker) -> Result<*mut Marker, Error> {
         let boxed_marker = Box::new(marker);
         let new_marker_raw = Box::into_raw(boxed_marker);
         let result = self.inner.marker.as_ref().unwrap().compare_exchange(
@@ -208,9 +338,19 @@ impl<K: Clone, V: Clone> CellGuard<'_, K, V> {
 
         if result.is_err() {
             // Deallocate memory, try again next time
-            unsafe { Box::from_raw(new_marker_raw) };
+            drop(unsafe { Box::from_raw(new_marker_raw) });
             // Marker has been updated by another process, start loop over
-            return Err(Box::new(CellWriteError {}));
+            Err(Error::Contended)
+        } else if self.inner.version.load(AtomicOrdering::SeqCst) != self.cache_version {
+            // a finished write hands its marker back, so only the version
+            // shows one came and went since the guard was taken
+            self.inner
+                .marker
+                .as_ref()
+                .unwrap()
+                .store(self.cache_marker_ptr, AtomicOrdering::SeqCst);
+            drop(unsafe { Box::from_raw(new_marker_raw) });
+            Err(Error::Contended)
         } else {
             let old_marker_box = self.cache_marker_ptr;
             self.cache_marker_ptr = new_marker_raw;
@@ -219,54 +359,33 @@ impl<K: Clone, V: Clone> CellGuard<'_, K, V> {
     }
 }
 
-#[derive(Debug)]
-pub struct CellReadError;
-#[derive(Debug)]
-pub struct CellWriteError;
-
-impl Display for CellReadError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CellReadError - Unable to read cell!")
-    }
-}
-
-impl Display for CellWriteError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CellWriteError - Unable to write cell!")
-    }
-}
-
-impl Error for CellReadError {}
-impl Error for CellWriteError {}
-
-impl<'a, K: Clone, V: Clone> CellGuard<'a, K, V> {
-    pub unsafe fn from_raw(ptr: *const Cell<K, V>) -> Result<CellGuard<'a, K, V>, Box<dyn Error>> {
+impl<'a, K, V> CellGuard<'a, K, V> {
+    pub unsafe fn from_raw(ptr: *const Cell<K, V>) -> Result<CellGuard<'a, K, V>, Error> {
         let cell = &*ptr;
         let version = cell.version.load(AtomicOrdering::SeqCst);
-        let key = (*cell.key.get()).clone();
+        let is_filled = cell.is_filled();
         let current_marker_raw = cell.marker.as_ref().unwrap().load(AtomicOrdering::SeqCst);
 
         // TODO: Check version in marker to make sure the cell was not modified in between
 
         Ok(CellGuard {
             inner: cell,
-            is_filled: key.is_some(),
+            is_filled,
             cache_version: version,
             cache_marker_ptr: current_marker_raw,
-            cache_data: OnceCell::new(),
             _phantom: PhantomData,
         })
     }
 }
 
-pub struct CellIterator<'a, K: Ord + Clone, V: Clone> {
+pub struct CellIterator<'a, K, V> {
     count: usize,
     address: *const Cell<K, V>,
     end_address: *const Cell<K, V>,
     _phantom: PhantomData<&'a Cell<K, V>>,
 }
 
-impl<'a, K: Clone + Ord, V: Clone> CellIterator<'a, K, V> {
+impl<'a, K, V> CellIterator<'a, K, V> {
     pub fn new(
         ptr: *const Cell<K, V>,
         last_cell_address: *const Cell<K, V>,
@@ -280,7 +399,7 @@ impl<'a, K: Clone + Ord, V: Clone> CellIterator<'a, K, V> {
     }
 }
 
-impl<'a, K: Ord + Clone, V: Clone> Iterator for CellIterator<'a, K, V> {
+impl<'a, K, V> Iterator for CellIterator<'a, K, V> {
     type Item = CellGuard<'a, K, V>;
 
     fn next(&mut self) -> Option<Self::Item> {
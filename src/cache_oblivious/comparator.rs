@@ -0,0 +1,19 @@
+use core::cmp::Ordering;
+
+// Orders the keys of a map. It's a value rather than a type so the order can
+// depend on runtime configuration, but it must stay the same for the life of
+// the map. Implementing it for a borrowed form of the key as well lets
+// lookups use that form, as with `Borrow` on the standard maps.
+pub trait Comparator<T: ?Sized> {
+    fn compare(&self, lhs: &T, rhs: &T) -> Ordering;
+}
+
+// The order given by `Ord`, used unless a map is built with another
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OrdComparator;
+
+impl<T: Ord + ?Sized> Comparator<T> for OrdComparator {
+    fn compare(&self, lhs: &T, rhs: &T) -> Ordering {
+        lhs.cmp(rhs)
+    }
+}
@@ -0,0 +1,46 @@
+use core::error;
+use core::fmt::{self, Display};
+#[cfg(feature = "std")]
+use std::io;
+
+// Why a fallible map operation gave up, the infallible ones panic with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    // No free cell is left where the key belongs, or the capacity asked for can't be addressed
+    CapacityExhausted,
+    // The allocator couldn't provide the cells or index nodes
+    AllocationFailed,
+    // A cell kept changing under other writers until retrying was given up
+    Contended,
+    // A thread panicked while holding the index lock
+    Poisoned,
+    // The write-ahead log of a durable map couldn't be written, and refuses writes from then on
+    #[cfg(feature = "std")]
+    Io(io::ErrorKind),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Error::CapacityExhausted => "out of cells",
+            Error::AllocationFailed => "allocation failed",
+            Error::Contended => "retries exhausted on a concurrently modified cell",
+            Error::Poisoned => "index lock poisoned",
+            #[cfg(feature = "std")]
+            Error::Io(kind) => return write!(f, "Error - log write failed: {}!", kind),
+        };
+        write!(f, "Error - {}!", reason)
+    }
+}
+
+impl error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::Io(kind) => io::Error::from(kind),
+            error => io::Error::other(error),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+
+use num_rational::Rational;
+
+use super::allocator;
+use super::cell::Cell;
+use super::packed_memory_array::{Config, Density, PackedMemoryArray};
+
+// Layout of a saved map, all integers little-endian:
+//
+//   magic "COBT", format version u16, requested capacity u32, cell count u64,
+//   density count u32, then per density: max item count u64 and the bounds
+//   of its range as (numer i64, denom i64) pairs,
+//   then per cell: 0u8 for a gap or 1u8 followed by the encoded key and value,
+//   and finally an FNV-1a checksum u64 of everything before it.
+const MAGIC: &[u8; 4] = b"COBT";
+const FORMAT_VERSION: u16 = 1;
+
+const EMPTY_CELL: u8 = 0;
+const FILLED_CELL: u8 = 1;
+
+// Keys and values written into the native format
+pub trait Encode: Sized {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! encode_int {
+    ($($t:ty),*) => {
+        $(
+            impl Encode for $t {
+                fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+
+                fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    reader.read_exact(&mut bytes)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+encode_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Encode for usize {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u64).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        u64::decode(reader)?
+            .try_into()
+            .map_err(|_| invalid(FormatError::Overflow))
+    }
+}
+
+impl Encode for bool {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u8).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok(u8::decode(reader)? != 0)
+    }
+}
+
+impl Encode for () {
+    fn encode<W: Write>(&self, _writer: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn decode<R: Read>(_reader: &mut R) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().encode(writer)?;
+        for item in self.iter() {
+            item.encode(writer)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = usize::decode(reader)?;
+        // the length hasn't been checksummed yet, don't trust it with an allocation
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(T::decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Encode for String {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().encode(writer)?;
+        writer.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = usize::decode(reader)?;
+        let mut bytes = Vec::new();
+        reader.take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        String::from_utf8(bytes).map_err(|_| invalid(FormatError::Encoding))
+    }
+}
+
+pub fn write_cells<K, V, W>(data: &PackedMemoryArray<Cell<K, V>>, writer: W) -> io::Result<()>
+where
+    K: Encode,
+    V: Encode,
+    W: Write,
+{
+    let mut writer = Checksummed::new(writer);
+
+    writer.write_all(MAGIC)?;
+    FORMAT_VERSION.encode(&mut writer)?;
+    data.requested_capacity.encode(&mut writer)?;
+    data.len().encode(&mut writer)?;
+
+    let density_scale = &data.config.density_scale;
+    (density_scale.len() as u32).encode(&mut writer)?;
+    for density in density_scale.iter() {
+        density.max_item_count.encode(&mut writer)?;
+        for bound in [density.range.start(), density.range.end()].iter() {
+            (*bound.numer() as i64).encode(&mut writer)?;
+            (*bound.denom() as i64).encode(&mut writer)?;
+        }
+    }
+
+    for cell in data.cells() {
+        match unsafe { cell.entry() } {
+            Some((key, value)) => {
+                FILLED_CELL.encode(&mut writer)?;
+                key.encode(&mut writer)?;
+                value.encode(&mut writer)?;
+            }
+            None => EMPTY_CELL.encode(&mut writer)?,
+        }
+    }
+
+    let checksum = writer.checksum;
+    checksum.encode(&mut writer.inner)?;
+    writer.inner.flush()
+}
+
+pub fn read_cells<K, V, R>(reader: R) -> io::Result<PackedMemoryArray<Cell<K, V>>>
+where
+    K: Encode,
+    V: Encode,
+    R: Read,
+{
+    let mut reader = Checksummed::new(reader);
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid(FormatError::Magic));
+    }
+    if u16::decode(&mut reader)? != FORMAT_VERSION {
+        return Err(invalid(FormatError::Version));
+    }
+
+    let requested_capacity = u32::decode(&mut reader)?;
+    let cell_count = usize::decode(&mut reader)?;
+    // arrays are always a power of two cells long, with a density for each power of two
+    let levels = cell_count.trailing_zeros();
+    if !cell_count.is_power_of_two() || levels < 4 {
+        return Err(invalid(FormatError::Layout));
+    }
+
+    let density_count = u32::decode(&mut reader)?;
+    if density_count != levels {
+        return Err(invalid(FormatError::Layout));
+    }
+    let mut density_scale = Vec::new();
+    for _ in 0..density_count {
+        let max_item_count = usize::decode(&mut reader)?;
+        let mut bounds = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let numer = i64::decode(&mut reader)?;
+            let denom = i64::decode(&mut reader)?;
+            if denom == 0 {
+                return Err(invalid(FormatError::Layout));
+            }
+            bounds.push(Rational::new_raw(numer as isize, denom as isize));
+        }
+        density_scale.push(Density {
+            max_item_count,
+            range: bounds[0]..=bounds[1],
+        });
+    }
+
+    let cells = PackedMemoryArray::<Cell<K, V>>::allocate_default(cell_count, allocator::global());
+    for cell in cells.iter() {
+        match u8::decode(&mut reader)? {
+            EMPTY_CELL => (),
+            FILLED_CELL => {
+                let key = K::decode(&mut reader)?;
+                let value = V::decode(&mut reader)?;
+                unsafe { cell.put(key, value) };
+            }
+            _ => return Err(invalid(FormatError::Layout)),
+        }
+    }
+
+    let checksum = reader.checksum;
+    if u64::decode(&mut reader.inner)? != checksum {
+        return Err(invalid(FormatError::Checksum));
+    }
+
+    let config = Config { density_scale };
+    Ok(PackedMemoryArray::from_parts(
+        cells,
+        requested_capacity,
+        config,
+    ))
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Magic,
+    Version,
+    Layout,
+    Encoding,
+    Overflow,
+    Checksum,
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            FormatError::Magic => "not a saved map",
+            FormatError::Version => "unsupported format version",
+            FormatError::Layout => "cells don't form a valid PackedMemoryArray",
+            FormatError::Encoding => "key or value is malformed",
+            FormatError::Overflow => "length doesn't fit in memory",
+            FormatError::Checksum => "checksum mismatch",
+        };
+        write!(f, "FormatError - {}!", reason)
+    }
+}
+
+impl Error for FormatError {}
+
+pub(super) fn invalid(error: FormatError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+pub(super) fn checksum(bytes: &[u8]) -> u64 {
+    let mut checksummed = Checksummed::new(());
+    checksummed.update(bytes);
+    checksummed.checksum
+}
+
+// Runs an FNV-1a checksum over every byte passing through
+struct Checksummed<T> {
+    inner: T,
+    checksum: u64,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Checksummed<T> {
+        Checksummed {
+            inner,
+            checksum: FNV_OFFSET_BASIS,
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.checksum ^= *byte as u64;
+            self.checksum = self.checksum.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.update(&buf[..read]);
+        Ok(read)
+    }
+}
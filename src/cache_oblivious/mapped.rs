@@ -0,0 +1,219 @@
+use std::cell::UnsafeCell;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicPtr, AtomicU16};
+
+use memmap2::MmapMut;
+
+use super::btree_map::MIN_CAPACITY;
+use super::cell::{Cell, Marker};
+use super::format::{invalid, FormatError};
+use super::packed_memory_array::{PackedMemoryArray, Storage};
+
+// Layout of a mapped file, header integers little-endian:
+//
+//   magic "COBM", format version u16, cell size u64, cell alignment u64,
+//   cell count u64, requested capacity u32, zero padding up to HEADER_LEN,
+//   then the cells themselves exactly as they sit in memory.
+//
+// Markers are heap pointers, so they are meaningless once the process that
+// wrote them is gone. Only presence bytes, keys and values are read back; every cell gets a
+// fresh marker when the file is opened. Keys and values are `Pod`, so any
+// bytes make valid ones, and each cell's presence byte is checked before
+// they're taken as an entry.
+const MAGIC: &[u8; 4] = b"COBM";
+// 2: values are no longer wrapped in an `Option`
+// 3: nor are keys, a presence byte says whether a cell holds an entry
+const FORMAT_VERSION: u16 = 3;
+// a page, which keeps the cells aligned for any key or value
+const HEADER_LEN: usize = 4096;
+
+/// Keys and values that can live in a file-backed map.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value, and the type must own nothing
+/// outside its own bytes, so it can be read straight back out of a file.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+pub struct MappedCells<T> {
+    map: MmapMut,
+    // the mapping never moves, even when this struct does
+    cells: *mut T,
+    // only initialized cells are counted
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T> MappedCells<T> {
+    fn new(mut map: MmapMut) -> MappedCells<T> {
+        let cells = unsafe { map.as_mut_ptr().add(HEADER_LEN) as *mut T };
+        MappedCells {
+            map,
+            cells,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.map.flush()
+    }
+
+    fn push(&mut self, cell: T) {
+        unsafe { self.cells.add(self.len).write(cell) };
+        self.len += 1;
+    }
+}
+
+impl<T> Deref for MappedCells<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.cells, self.len) }
+    }
+}
+
+impl<T> Drop for MappedCells<T> {
+    fn drop(&mut self) {
+        // frees the markers, keys and values stay behind in the file
+        unsafe { ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.cells, self.len)) };
+    }
+}
+
+// Opens the array stored at `path`, creating it with room for `capacity`
+// keys if the file is empty. An existing file keeps the capacity it was
+// created with.
+pub fn open_cells<K: Pod, V: Pod>(
+    path: &Path,
+    capacity: u32,
+) -> io::Result<PackedMemoryArray<Cell<K, V>>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    let (cells, capacity) = if file.metadata()?.len() == 0 {
+        create::<K, V>(&file, capacity.max(MIN_CAPACITY))?
+    } else {
+        reopen::<K, V>(&file)?
+    };
+
+    Ok(PackedMemoryArray::with_storage(
+        Storage::Mapped(cells),
+        capacity,
+    ))
+}
+
+fn create<K: Pod, V: Pod>(
+    file: &File,
+    capacity: u32,
+) -> io::Result<(MappedCells<Cell<K, V>>, u32)> {
+    let cell_count = PackedMemoryArray::<Cell<K, V>>::allocation_size(capacity)
+        .ok_or_else(|| invalid(FormatError::Overflow))?;
+    file.set_len(file_len::<K, V>(cell_count)? as u64)?;
+
+    let mut map = unsafe { MmapMut::map_mut(file)? };
+    let header = &mut map[..HEADER_LEN];
+    header[0..4].copy_from_slice(MAGIC);
+    header[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header[6..14].copy_from_slice(&(mem::size_of::<Cell<K, V>>() as u64).to_le_bytes());
+    header[14..22].copy_from_slice(&(mem::align_of::<Cell<K, V>>() as u64).to_le_bytes());
+    header[22..30].copy_from_slice(&(cell_count as u64).to_le_bytes());
+    header[30..34].copy_from_slice(&capacity.to_le_bytes());
+
+    let mut cells = MappedCells::new(map);
+    for _ in 0..cell_count {
+        cells.push(Cell::default());
+    }
+
+    Ok((cells, capacity))
+}
+
+fn reopen<K: Pod, V: Pod>(file: &File) -> io::Result<(MappedCells<Cell<K, V>>, u32)> {
+    let map = unsafe { MmapMut::map_mut(file)? };
+    if map.len() < HEADER_LEN || &map[0..4] != MAGIC {
+        return Err(invalid(FormatError::Magic));
+    }
+    if u16::from_le_bytes(map[4..6].try_into().unwrap()) != FORMAT_VERSION {
+        return Err(invalid(FormatError::Version));
+    }
+
+    let cell_size = u64::from_le_bytes(map[6..14].try_into().unwrap());
+    let cell_align = u64::from_le_bytes(map[14..22].try_into().unwrap());
+    let cell_count = u64::from_le_bytes(map[22..30].try_into().unwrap());
+    let capacity = u32::from_le_bytes(map[30..34].try_into().unwrap());
+
+    // written by a build with different key or value types
+    if cell_size != mem::size_of::<Cell<K, V>>() as u64
+        || cell_align != mem::align_of::<Cell<K, V>>() as u64
+    {
+        return Err(invalid(FormatError::Layout));
+    }
+    let cell_count: usize = cell_count
+        .try_into()
+        .map_err(|_| invalid(FormatError::Overflow))?;
+    // at least the cells `capacity` needs, and a power of two so the index stays complete
+    let needed = PackedMemoryArray::<Cell<K, V>>::allocation_size(capacity);
+    if !cell_count.is_power_of_two()
+        || capacity < MIN_CAPACITY
+        || needed.is_none_or(|needed| cell_count < needed)
+        || map.len() != file_len::<K, V>(cell_count)?
+    {
+        return Err(invalid(FormatError::Layout));
+    }
+
+    let mut cells = MappedCells::<Cell<K, V>>::new(map);
+    for i in 0..cell_count {
+        // the stale marker pointer is overwritten, never dropped
+        let (filled, key, value) = unsafe {
+            let cell = cells.cells.add(i);
+            (
+                ptr::addr_of!((*cell).filled).cast::<u8>().read(),
+                ptr::addr_of!((*cell).key).read(),
+                ptr::addr_of!((*cell).value).read(),
+            )
+        };
+        // only a bool's two bit patterns say whether the key and value are there
+        let filled = match filled {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid(FormatError::Encoding)),
+        };
+        cells.push(Cell {
+            version: AtomicU16::new(1),
+            filled: UnsafeCell::new(filled),
+            marker: Some(AtomicPtr::new(Marker::fresh())),
+            key,
+            value,
+        });
+    }
+
+    Ok((cells, capacity))
+}
+
+fn file_len<K: Pod, V: Pod>(cell_count: usize) -> io::Result<usize> {
+    mem::size_of::<Cell<K, V>>()
+        .checked_mul(cell_count)
+        .and_then(|len| len.checked_add(HEADER_LEN))
+        .ok_or_else(|| invalid(FormatError::Overflow))
+}
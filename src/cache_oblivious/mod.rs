@@ -1,7 +1,38 @@
 // mod binary_tree;
 // mod packed_data;
+mod allocator;
 mod btree_map;
+mod btree_set;
+mod bytes_map;
 mod cell;
+mod comparator;
+mod error;
+#[cfg(feature = "std")]
+mod format;
+#[cfg(feature = "mmap")]
+mod mapped;
+mod monoid;
 mod packed_memory_array;
+#[cfg(feature = "serde")]
+mod serde_impls;
+mod slab_map;
+mod snapshot;
+mod sync;
+mod transaction;
+#[cfg(feature = "std")]
+mod wal;
 
+pub use allocator::{Allocator, Global};
 pub use btree_map::BTreeMap;
+pub use btree_set::BTreeSet;
+pub use bytes_map::BytesMap;
+pub use comparator::{Comparator, OrdComparator};
+pub use error::Error;
+#[cfg(feature = "std")]
+pub use format::{Encode, FormatError};
+#[cfg(feature = "mmap")]
+pub use mapped::Pod;
+pub use monoid::Monoid;
+pub use slab_map::SlabMap;
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
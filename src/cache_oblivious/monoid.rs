@@ -0,0 +1,23 @@
+// Summarises the entries of a subtree so range queries can combine cached
+// results instead of visiting every cell. `combine` must be associative and
+// `identity` must leave any summary unchanged when combined with it.
+pub trait Monoid<K, V> {
+    type Summary: Clone;
+
+    fn identity() -> Self::Summary;
+
+    fn lift(key: &K, value: &V) -> Self::Summary;
+
+    fn combine(lhs: &Self::Summary, rhs: &Self::Summary) -> Self::Summary;
+}
+
+// Maps that don't need aggregates pay nothing for them
+impl<K, V> Monoid<K, V> for () {
+    type Summary = ();
+
+    fn identity() {}
+
+    fn lift(_key: &K, _value: &V) {}
+
+    fn combine(_lhs: &(), _rhs: &()) {}
+}
@@ -1,38 +1,102 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::marker::{PhantomData, PhantomPinned};
+use core::ops::Deref;
+use core::ops::Range;
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+
 use num_rational::Rational;
-use std::fmt::{self, Debug};
-use std::marker::{PhantomData, PhantomPinned};
-use std::ops::Range;
-use std::ops::RangeInclusive;
-use std::pin::Pin;
+
+use super::allocator::{Allocation, Allocator};
+use super::error::Error;
+#[cfg(feature = "mmap")]
+use super::mapped::MappedCells;
+
+// Where the cells live. Either way they never move once allocated.
+pub enum Storage<T> {
+    Heap(Allocation<T>),
+    #[cfg(feature = "mmap")]
+    Mapped(MappedCells<T>),
+}
+
+impl<T> Deref for Storage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Storage::Heap(cells) => cells,
+            #[cfg(feature = "mmap")]
+            Storage::Mapped(cells) => cells,
+        }
+    }
+}
 
 pub struct PackedMemoryArray<T> {
-    cells: Pin<Box<[T]>>,
+    cells: Storage<T>,
     pub config: Config,
     pub active_range: Range<*const T>,
     pub requested_capacity: u32, // todo: Temporary...
+    writes: WriteLog,
     _pin: PhantomPinned,
 }
 
+// Which blocks were written when, so an index built before a write knows
+// which of its leaves no longer match the cells
+struct WriteLog {
+    sequence: AtomicUsize,
+    // the sequence of each block's latest write not yet seen by an index, 0 once it has been
+    blocks: Box<[AtomicUsize]>,
+    // set while some block may be ON_LOAN, so dating them needn't scan otherwise
+    lent: AtomicBool,
+}
+
+// Stands in for the sequence of a block whose values may still be changing
+const ON_LOAN: usize = usize::MAX;
+
 unsafe impl<T> Send for PackedMemoryArray<T> {}
 unsafe impl<T> Sync for PackedMemoryArray<T> {}
 
 impl<T> PackedMemoryArray<T> {
-    pub fn new(cells: Box<[T]>, capacity: u32) -> PackedMemoryArray<T> {
+    pub fn new(cells: Allocation<T>, capacity: u32) -> PackedMemoryArray<T> {
+        Self::with_storage(Storage::Heap(cells), capacity)
+    }
+
+    pub fn from_parts(cells: Allocation<T>, capacity: u32, config: Config) -> PackedMemoryArray<T> {
+        Self::from_storage(Storage::Heap(cells), capacity, config)
+    }
+
+    pub fn with_storage(cells: Storage<T>, capacity: u32) -> PackedMemoryArray<T> {
+        let density_scale = Self::compute_density_range(cells.len());
+        let config = Config { density_scale };
+        Self::from_storage(cells, capacity, config)
+    }
+
+    fn from_storage(cells: Storage<T>, capacity: u32, config: Config) -> PackedMemoryArray<T> {
         let left_buffer_space = cells.len() >> 2;
 
         // TODO: Generalize this
-        let active_range = std::ops::Range {
+        let active_range = core::ops::Range {
             start: &cells[left_buffer_space] as *const _,
             end: &cells[cells.len() - left_buffer_space] as *const _,
         };
 
-        let density_scale = Self::compute_density_range(cells.len() as f32);
-        let config = Config { density_scale };
+        // one past the last block too, `active_range.end` is itself a cell
+        let block_count =
+            (cells.len() - 2 * left_buffer_space) / Self::block_len_for(cells.len()) + 1;
 
         PackedMemoryArray {
-            cells: Box::into_pin(cells),
+            cells,
             requested_capacity: capacity,
             active_range,
+            writes: WriteLog {
+                sequence: AtomicUsize::new(0),
+                blocks: (0..block_count).map(|_| AtomicUsize::new(0)).collect(),
+                lent: AtomicBool::new(false),
+            },
             config,
             _pin: PhantomPinned,
         }
@@ -43,6 +107,32 @@ impl<T> PackedMemoryArray<T> {
         &self.cells[left_buffer_space..self.cells.len() - left_buffer_space]
     }
 
+    // Every cell, including the buffer space either side of the active range
+    pub fn cells(&self) -> &[T] {
+        &self.cells
+    }
+
+    pub fn storage(&self) -> &Storage<T> {
+        &self.cells
+    }
+
+    // Where a larger array for the same cells should come from
+    pub fn allocator(&self) -> Arc<dyn Allocator> {
+        match &self.cells {
+            Storage::Heap(cells) => Arc::clone(cells.allocator()),
+            #[cfg(feature = "mmap")]
+            Storage::Mapped(_) => super::allocator::global(),
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        match self.cells {
+            Storage::Heap(_) => false,
+            #[cfg(feature = "mmap")]
+            Storage::Mapped(_) => true,
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.cells.len()
     }
@@ -51,8 +141,95 @@ impl<T> PackedMemoryArray<T> {
         self.active_range.contains(ptr)
     }
 
-    fn compute_density_range(cell_count: f32) -> Vec<Density> {
-        let num_densities = f32::log2(cell_count) as isize;
+    pub fn index_of(&self, ptr: *const T) -> usize {
+        let offset = unsafe { ptr.offset_from(self.cells.as_ptr()) };
+        offset.try_into().unwrap()
+    }
+
+    // Cells per block of the active range, the unit an index has a leaf for.
+    // Rounding log2 of the size up to a power of two makes them tile it exactly.
+    pub fn block_len(&self) -> usize {
+        Self::block_len_for(self.cells.len())
+    }
+
+    fn block_len_for(cell_count: usize) -> usize {
+        (cell_count.ilog2().next_power_of_two() as usize / 2).max(1)
+    }
+
+    // Number of the block `ptr` sits in, counting from the start of the active range
+    pub fn block_of(&self, ptr: *const T) -> usize {
+        (self.index_of(ptr) - (self.cells.len() >> 2)) / self.block_len()
+    }
+
+    // Writes logged so far. An index built after reading this has seen every
+    // one of them, those logged later it may or may not have. Blocks whose
+    // values are still out on loan are dated after it, so this index keeps
+    // recounting them and only the next one trusts its own copy.
+    pub fn write_sequence(&self) -> usize {
+        let sequence = self.writes.sequence.load(AtomicOrdering::SeqCst);
+        if self.writes.lent.swap(false, AtomicOrdering::SeqCst) {
+            let dated = self.writes.sequence.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+            for written in self.writes.blocks.iter() {
+                let _ = written.compare_exchange(
+                    ON_LOAN,
+                    dated,
+                    AtomicOrdering::SeqCst,
+                    AtomicOrdering::SeqCst,
+                );
+            }
+        }
+        sequence
+    }
+
+    // Called once a write to the cell at `ptr` has landed
+    pub fn mark_written(&self, ptr: *const T) {
+        let block = self.block_of(ptr);
+        let sequence = self.writes.sequence.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+        self.writes.blocks[block].store(sequence, AtomicOrdering::SeqCst);
+    }
+
+    // Called as a value in the cell at `ptr` is handed out to be changed in
+    // place, which may go on until the map is next used
+    pub fn mark_lent(&self, ptr: *const T) {
+        let block = self.block_of(ptr);
+        self.writes.blocks[block].store(ON_LOAN, AtomicOrdering::SeqCst);
+        self.writes.lent.store(true, AtomicOrdering::SeqCst);
+        // an index reading the sequence after this goes looking for the block
+        self.writes.sequence.fetch_add(1, AtomicOrdering::SeqCst);
+    }
+
+    // Blocks written after `sequence`, in order
+    pub fn written_since(&self, sequence: usize) -> Vec<usize> {
+        if self.writes.sequence.load(AtomicOrdering::SeqCst) <= sequence {
+            return Vec::new();
+        }
+        self.writes
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, written)| written.load(AtomicOrdering::SeqCst) > sequence)
+            .map(|(block, _)| block)
+            .collect()
+    }
+
+    // Clears the blocks an index built from `sequence` onwards has already
+    // seen, leaving any written again in the meantime
+    pub fn forget_writes(&self, sequence: usize) {
+        for written in self.writes.blocks.iter() {
+            let seen = written.load(AtomicOrdering::SeqCst);
+            if seen != 0 && seen <= sequence {
+                let _ = written.compare_exchange(
+                    seen,
+                    0,
+                    AtomicOrdering::SeqCst,
+                    AtomicOrdering::SeqCst,
+                );
+            }
+        }
+    }
+
+    fn compute_density_range(cell_count: usize) -> Vec<Density> {
+        let num_densities = cell_count.ilog2() as isize;
 
         // max density for 2^num_densities cells: 1/2
         let t_min = Rational::new(1, 2);
@@ -77,16 +254,16 @@ impl<T> PackedMemoryArray<T> {
             .collect::<Vec<_>>()
     }
 
-    fn allocation_size(num_keys: u32) -> u32 {
-        let t_min = 0.5;
-        let p_max = 0.25;
-        let ideal_density = (t_min - p_max) / 2f32;
-
-        let length = num_keys as f32 / ideal_density;
-        // To get a balanced tree, we need to find the
-        // closest double-exponential number (x = 2^2^i)
-        let clean_length = 2 << ((f32::log2(length).ceil() as u32).next_power_of_two() - 1);
-        clean_length
+    // None if the cells for `num_keys` can't be addressed
+    pub fn allocation_size(num_keys: u32) -> Option<usize> {
+        // only the middle half of the cells is active, so this packs the keys at
+        // p_max (1/4), the root's lowest density, leaving room to double before t_min (1/2)
+        let length = (num_keys as u64 * 8).next_power_of_two();
+        // arrays past 2^32 cells are turned down rather than attempted
+        if length > 1 << 32 {
+            return None;
+        }
+        length.try_into().ok()
     }
 }
 
@@ -94,17 +271,30 @@ impl<T> PackedMemoryArray<T>
 where
     T: Default,
 {
-    pub fn with_capacity(capacity: u32) -> PackedMemoryArray<T> {
-        let size = Self::allocation_size(capacity);
-        // println!("packed memory array [V; {:?}]", size);
-        let initialized_cells = Self::allocate_default(size as usize);
+    pub fn with_capacity(capacity: u32, allocator: Arc<dyn Allocator>) -> PackedMemoryArray<T> {
+        let size = Self::allocation_size(capacity).expect("capacity too large");
+        let initialized_cells = Self::allocate_default(size, allocator);
         PackedMemoryArray::new(initialized_cells, capacity)
     }
 
-    fn allocate_default(size: usize) -> Box<[T]> {
-        let mut vec = Vec::with_capacity(size);
-        vec.resize_with(size, Default::default);
-        vec.into_boxed_slice()
+    pub fn try_with_capacity(
+        capacity: u32,
+        allocator: Arc<dyn Allocator>,
+    ) -> Result<PackedMemoryArray<T>, Error> {
+        let size = Self::allocation_size(capacity).ok_or(Error::CapacityExhausted)?;
+        let initialized_cells = Self::try_allocate_default(size, allocator)?;
+        Ok(PackedMemoryArray::new(initialized_cells, capacity))
+    }
+
+    pub fn allocate_default(size: usize, allocator: Arc<dyn Allocator>) -> Allocation<T> {
+        Allocation::from_fn(size, allocator, Default::default)
+    }
+
+    pub fn try_allocate_default(
+        size: usize,
+        allocator: Arc<dyn Allocator>,
+    ) -> Result<Allocation<T>, Error> {
+        Allocation::try_from_fn(size, allocator, Default::default)
     }
 }
 
@@ -115,19 +305,19 @@ where
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         formatter
             .debug_struct("PackedMemoryArray")
-            .field("cells", &format_args!("{:?}", self.cells))
+            .field("cells", &format_args!("{:?}", &*self.cells))
             .finish()
     }
 }
 
-impl<'a, T> std::iter::IntoIterator for &'a PackedMemoryArray<T> {
+impl<'a, T> core::iter::IntoIterator for &'a PackedMemoryArray<T> {
     type Item = &'a T;
     type IntoIter = Iter<'a, T>;
 
     fn into_iter(self) -> Iter<'a, T> {
         Iter {
             ptr: self.active_range.start,
-            active_range: std::ops::Range {
+            active_range: core::ops::Range {
                 start: self.active_range.start,
                 end: self.active_range.end,
             },
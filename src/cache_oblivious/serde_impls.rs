@@ -0,0 +1,74 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::iter::FromIterator;
+use core::marker::PhantomData;
+
+use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+
+use super::btree_map::BTreeMap;
+use super::comparator::Comparator;
+use super::monoid::Monoid;
+
+// Entries reserved up front at most, the length a sequence claims comes from
+// the input and may be nowhere near the truth
+const MAX_RESERVED_ENTRIES: usize = 4096;
+
+// Entries are written as an ordered sequence of (key, value) pairs, so the
+// physical layout is left behind and rebuilt to suit the receiving map
+impl<K, V, M, C> Serialize for BTreeMap<K, V, M, C>
+where
+    K: 'static + Serialize,
+    V: 'static + Serialize,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // formats like bincode need the length up front
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for entry in self.iter() {
+            seq.serialize_element(&entry)?;
+        }
+        seq.end()
+    }
+}
+
+// the comparator isn't part of the data, the receiving map brings its own
+impl<'de, K, V, M, C> Deserialize<'de> for BTreeMap<K, V, M, C>
+where
+    K: 'static + Deserialize<'de>,
+    V: 'static + Deserialize<'de>,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(EntriesVisitor(PhantomData))
+    }
+}
+
+struct EntriesVisitor<K, V, M, C>(PhantomData<(K, V, M, C)>);
+
+impl<'de, K, V, M, C> Visitor<'de> for EntriesVisitor<K, V, M, C>
+where
+    K: 'static + Deserialize<'de>,
+    V: 'static + Deserialize<'de>,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync + Default,
+{
+    type Value = BTreeMap<K, V, M, C>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a sequence of key-value pairs")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let reserved = seq.size_hint().unwrap_or(0).min(MAX_RESERVED_ENTRIES);
+        let mut entries = Vec::with_capacity(reserved);
+        while let Some(entry) = seq.next_element::<(K, V)>()? {
+            entries.push(entry);
+        }
+
+        // indexed once, with room to keep growing
+        Ok(BTreeMap::from_iter(entries))
+    }
+}
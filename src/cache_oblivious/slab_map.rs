@@ -0,0 +1,219 @@
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::mem;
+use core::ops::RangeBounds;
+
+use super::btree_map::{self, BTreeMap};
+use super::comparator::{Comparator, OrdComparator};
+
+// A map that keeps its values out of the cells. Each cell holds the key and a
+// fixed-size handle into a slab, so rebalancing only ever moves keys and
+// handles and a scan over the keys touches no value bytes. Values stay where
+// they were put until they're removed.
+pub struct SlabMap<K, V, C = OrdComparator>
+where
+    K: 'static,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    map: BTreeMap<K, Handle, (), C>,
+    slab: Slab<V>,
+}
+
+impl<K, V> SlabMap<K, V>
+where
+    K: 'static + Ord,
+{
+    pub fn new(capacity: u32) -> SlabMap<K, V> {
+        Self::with_comparator(capacity, OrdComparator)
+    }
+}
+
+impl<K, V, C> SlabMap<K, V, C>
+where
+    K: 'static,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    pub fn with_comparator(capacity: u32, comparator: C) -> SlabMap<K, V, C> {
+        SlabMap {
+            map: BTreeMap::with_comparator(capacity, comparator),
+            slab: Slab::with_capacity(capacity as usize),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slab.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slab.len == 0
+    }
+
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let handle = self.map.get(key)?;
+        Some(self.slab.get(*handle))
+    }
+
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let handle = *self.map.get(key)?;
+        Some(self.slab.get_mut(handle))
+    }
+
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        self.map.get(key).is_some()
+    }
+
+    // An existing key keeps its slot, only the value in it is swapped
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(handle) = self.map.get(&key) {
+            return Some(mem::replace(self.slab.get_mut(*handle), value));
+        }
+
+        let handle = self.slab.insert(value);
+        self.map.insert(key, handle);
+        None
+    }
+
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let handle = self.map.remove(key)?;
+        Some(self.slab.remove(handle))
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.map.iter(),
+            slab: &self.slab,
+        }
+    }
+
+    pub fn range<Q: ?Sized, R>(&self, range: R) -> Iter<'_, K, V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
+    {
+        Iter {
+            inner: self.map.range(range),
+            slab: &self.slab,
+        }
+    }
+}
+
+impl<K, V, C> Debug for SlabMap<K, V, C>
+where
+    K: 'static + Debug,
+    V: Debug,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Handle(u32);
+
+enum Slot<V> {
+    Occupied(V),
+    // the next vacant slot, forming a free list
+    Vacant(Option<Handle>),
+}
+
+struct Slab<V> {
+    slots: Vec<Slot<V>>,
+    next_vacant: Option<Handle>,
+    len: usize,
+}
+
+impl<V> Slab<V> {
+    fn with_capacity(capacity: usize) -> Slab<V> {
+        Slab {
+            slots: Vec::with_capacity(capacity),
+            next_vacant: None,
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, value: V) -> Handle {
+        self.len += 1;
+        match self.next_vacant {
+            Some(handle) => {
+                let slot = &mut self.slots[handle.0 as usize];
+                match mem::replace(slot, Slot::Occupied(value)) {
+                    Slot::Vacant(next) => self.next_vacant = next,
+                    Slot::Occupied(_) => unreachable!("free list points at an occupied slot"),
+                }
+                handle
+            }
+            None => {
+                let handle = Handle(self.slots.len().try_into().expect("slab is full"));
+                self.slots.push(Slot::Occupied(value));
+                handle
+            }
+        }
+    }
+
+    fn remove(&mut self, handle: Handle) -> V {
+        let slot = &mut self.slots[handle.0 as usize];
+        match mem::replace(slot, Slot::Vacant(self.next_vacant)) {
+            Slot::Occupied(value) => {
+                self.next_vacant = Some(handle);
+                self.len -= 1;
+                value
+            }
+            Slot::Vacant(_) => unreachable!("handle to a vacant slot"),
+        }
+    }
+
+    fn get(&self, handle: Handle) -> &V {
+        match &self.slots[handle.0 as usize] {
+            Slot::Occupied(value) => value,
+            Slot::Vacant(_) => unreachable!("handle to a vacant slot"),
+        }
+    }
+
+    fn get_mut(&mut self, handle: Handle) -> &mut V {
+        match &mut self.slots[handle.0 as usize] {
+            Slot::Occupied(value) => value,
+            Slot::Vacant(_) => unreachable!("handle to a vacant slot"),
+        }
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    inner: btree_map::Iter<'a, K, Handle>,
+    slab: &'a Slab<V>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, handle) = self.inner.next()?;
+        Some((key, self.slab.get(*handle)))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (key, handle) = self.inner.next_back()?;
+        Some((key, self.slab.get(*handle)))
+    }
+}
@@ -0,0 +1,213 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::borrow::Borrow;
+use core::cmp::Ordering;
+use core::fmt::{self, Debug};
+use core::ops::{Bound, RangeBounds};
+use core::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+use super::btree_map::BlockIndex;
+use super::cell::Cell;
+use super::comparator::{Comparator, OrdComparator};
+use super::monoid::Monoid;
+use super::sync::Mutex;
+
+// Cells are retained copy-on-write: a writer hands each cell to every live
+// snapshot before mutating it, so the snapshot keeps the contents it had when
+// the snapshot was taken. Cells that were never retained are unchanged and
+// can be read straight from the shared PackedMemoryArray.
+pub struct SnapshotState<K, V> {
+    retained: Mutex<BTreeMap<usize, Option<(K, V)>>>,
+    // the map itself doesn't require Clone, so the state keeps its own
+    read_cell: ReadCell<K, V>,
+    // the map's count of live snapshots, this one included
+    live: Arc<AtomicUsize>,
+}
+
+type ReadCell<K, V> = fn(&Cell<K, V>) -> Option<(K, V)>;
+
+impl<K: Clone, V: Clone> SnapshotState<K, V> {
+    pub fn new(live: Arc<AtomicUsize>) -> Self {
+        live.fetch_add(1, AtomicOrdering::AcqRel);
+        SnapshotState {
+            retained: Mutex::new(BTreeMap::new()),
+            read_cell: Self::read_cell,
+            live,
+        }
+    }
+
+    fn read(&self, offset: usize, cell: &Cell<K, V>) -> Option<(K, V)> {
+        // holding the lock keeps writers from touching cells we haven't retained yet
+        let retained = self.retained.lock().unwrap();
+        match retained.get(&offset) {
+            Some(data) => data.clone(),
+            None => Self::read_cell(cell),
+        }
+    }
+
+    fn read_cell(cell: &Cell<K, V>) -> Option<(K, V)> {
+        unsafe { cell.entry() }.map(|(key, value)| (key.clone(), value.clone()))
+    }
+}
+
+impl<K, V> Drop for SnapshotState<K, V> {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, AtomicOrdering::AcqRel);
+    }
+}
+
+impl<K, V> SnapshotState<K, V> {
+    pub fn preserve(&self, offset: usize, cell: &Cell<K, V>) {
+        let mut retained = self.retained.lock().unwrap();
+        retained
+            .entry(offset)
+            .or_insert_with(|| (self.read_cell)(cell));
+    }
+}
+
+pub struct Snapshot<K, V, M: Monoid<K, V> = (), C = OrdComparator> {
+    index: BlockIndex<K, V, M, C>,
+    state: Arc<SnapshotState<K, V>>,
+}
+
+// the retained cells are only behind a real lock with std
+#[cfg(feature = "std")]
+unsafe impl<K: Send, V: Send, M: Monoid<K, V>, C: Send + Sync> Send for Snapshot<K, V, M, C> {}
+#[cfg(feature = "std")]
+unsafe impl<K: Send, V: Send, M: Monoid<K, V>, C: Send + Sync> Sync for Snapshot<K, V, M, C> {}
+
+impl<K, V, M, C> Snapshot<K, V, M, C>
+where
+    K: Clone,
+    V: Clone,
+    M: Monoid<K, V>,
+    C: Comparator<K>,
+{
+    pub fn new(
+        index: BlockIndex<K, V, M, C>,
+        state: Arc<SnapshotState<K, V>>,
+    ) -> Snapshot<K, V, M, C> {
+        Snapshot { index, state }
+    }
+
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        // a block whose min key has since been written may still hold ours
+        let start = self.index.block_start(key, true)?;
+
+        for (k, v) in self.entries_between(start, self.end_ptr()) {
+            match self.index.comparator.compare(k.borrow(), key) {
+                Ordering::Equal => return Some(v),
+                Ordering::Greater => return None,
+                Ordering::Less => (),
+            }
+        }
+
+        None
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V, M, C> {
+        self.entries_between(self.index.map.active_range.start, self.end_ptr())
+    }
+
+    pub fn range<Q: ?Sized, R>(&self, range: R) -> Iter<'_, K, V, M, C>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+        R: RangeBounds<Q>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.position_after(key, false),
+            Bound::Excluded(key) => self.position_after(key, true),
+            Bound::Unbounded => self.index.map.active_range.start,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.position_after(key, true),
+            Bound::Excluded(key) => self.position_after(key, false),
+            Bound::Unbounded => self.end_ptr(),
+        };
+
+        self.entries_between(start, end.max(start))
+    }
+
+    // Address of the first cell holding a key greater than (or equal to, unless
+    // `skip_equal` is set) the search key.
+    fn position_after<Q: ?Sized>(&self, key: &Q, skip_equal: bool) -> *const Cell<K, V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let mut address = self
+            .index
+            .block_start(key, true)
+            .unwrap_or(self.index.map.active_range.start);
+        while address < self.end_ptr() {
+            let offset = self.index.map.index_of(address);
+            if let Some((k, _)) = self.state.read(offset, unsafe { &*address }) {
+                match self.index.comparator.compare(k.borrow(), key) {
+                    Ordering::Greater => break,
+                    Ordering::Equal if !skip_equal => break,
+                    _ => (),
+                }
+            }
+            address = unsafe { address.add(1) };
+        }
+        address
+    }
+
+    fn end_ptr(&self) -> *const Cell<K, V> {
+        // active_range.end is the last usable cell
+        unsafe { self.index.map.active_range.end.add(1) }
+    }
+
+    fn entries_between(
+        &self,
+        start: *const Cell<K, V>,
+        end: *const Cell<K, V>,
+    ) -> Iter<'_, K, V, M, C> {
+        Iter {
+            snapshot: self,
+            address: start,
+            end_address: end,
+        }
+    }
+}
+
+impl<K, V, M, C> Debug for Snapshot<K, V, M, C>
+where
+    K: Clone + Debug,
+    V: Clone + Debug,
+    M: Monoid<K, V>,
+    C: Comparator<K>,
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.debug_map().entries(self.iter()).finish()
+    }
+}
+
+pub struct Iter<'a, K, V, M: Monoid<K, V>, C = OrdComparator> {
+    snapshot: &'a Snapshot<K, V, M, C>,
+    address: *const Cell<K, V>,
+    end_address: *const Cell<K, V>,
+}
+
+impl<'a, K: Clone, V: Clone, M: Monoid<K, V>, C> Iterator for Iter<'a, K, V, M, C> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.address < self.end_address {
+            let cell = unsafe { &*self.address };
+            let offset = self.snapshot.index.map.index_of(self.address);
+            self.address = unsafe { self.address.add(1) };
+
+            if let Some(entry) = self.snapshot.state.read(offset, cell) {
+                return Some(entry);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,42 @@
+// Locks around the index and snapshot state. With std they're the real thing.
+// Without it there's no other thread to wait on, so a borrow flag will do,
+// and taking a lock that's already held is a bug rather than a wait.
+#[cfg(feature = "std")]
+pub use std::sync::{Mutex, RwLock};
+
+#[cfg(not(feature = "std"))]
+use core::cell::{Ref, RefCell, RefMut};
+#[cfg(not(feature = "std"))]
+use core::convert::Infallible;
+
+#[cfg(not(feature = "std"))]
+pub struct Mutex<T>(RefCell<T>);
+
+#[cfg(not(feature = "std"))]
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex(RefCell::new(value))
+    }
+
+    pub fn lock(&self) -> Result<RefMut<'_, T>, Infallible> {
+        Ok(self.0.borrow_mut())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub struct RwLock<T>(RefCell<T>);
+
+#[cfg(not(feature = "std"))]
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock(RefCell::new(value))
+    }
+
+    pub fn read(&self) -> Result<Ref<'_, T>, Infallible> {
+        Ok(self.0.borrow())
+    }
+
+    pub fn write(&self) -> Result<RefMut<'_, T>, Infallible> {
+        Ok(self.0.borrow_mut())
+    }
+}
@@ -0,0 +1,124 @@
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+
+use super::btree_map::BTreeMap;
+use super::cell::CellGuard;
+use super::comparator::{Comparator, OrdComparator};
+use super::monoid::Monoid;
+
+pub const MAX_TRANSACTION_ATTEMPTS: usize = 8;
+
+// Reads go straight to the map and remember the cells they came from, writes
+// are buffered until commit. At commit every cell that was read must still
+// hold the version and marker it had when it was read. A key found missing
+// is read through the cells either side of where it would go.
+pub struct Transaction<'a, K, V, M = (), C = OrdComparator>
+where
+    K: 'static,
+    V: 'static,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    map: &'a BTreeMap<K, V, M, C>,
+    reads: Vec<CellGuard<'a, K, V>>,
+    // set when a read caught the map mid-change, this attempt can't commit
+    conflicted: bool,
+    // kept sorted by the map's comparator, None marks a removal
+    writes: Vec<(K, Option<V>)>,
+}
+
+impl<'a, K, V, M, C> Transaction<'a, K, V, M, C>
+where
+    K: 'static + Clone,
+    V: 'static + Clone,
+    M: 'static + Monoid<K, V>,
+    C: 'static + Comparator<K> + Send + Sync,
+{
+    pub fn new(map: &'a BTreeMap<K, V, M, C>) -> Transaction<'a, K, V, M, C> {
+        Transaction {
+            map,
+            reads: Vec::new(),
+            conflicted: false,
+            writes: Vec::new(),
+        }
+    }
+
+    pub fn get<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        if let Ok(position) = self.write_position(key) {
+            return self.writes[position].1.clone();
+        }
+
+        self.read(key).map(|(_, value)| value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.write(key, Some(value));
+    }
+
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        if let Ok(position) = self.write_position(key) {
+            return self.writes[position].1.take();
+        }
+
+        let (key, value) = self.read(key)?;
+        self.write(key, None);
+        Some(value)
+    }
+
+    fn read<Q: ?Sized>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        match self.map.find_cell(key) {
+            Some(mut cell_guard) => {
+                // a cell emptied or rewritten since it was read fails validation
+                let entry =
+                    cell_guard.read(|entry| entry.map(|(key, value)| (key.clone(), value.clone())));
+                self.conflicted |= entry.is_err();
+                self.reads.push(cell_guard);
+                entry.ok().flatten()
+            }
+            None => {
+                match self.map.gap_cells(key) {
+                    Some(cells) => self.reads.extend(cells),
+                    None => self.conflicted = true,
+                }
+                None
+            }
+        }
+    }
+
+    fn write(&mut self, key: K, value: Option<V>) {
+        match self.write_position(&key) {
+            Ok(position) => self.writes[position].1 = value,
+            Err(position) => self.writes.insert(position, (key, value)),
+        }
+    }
+
+    fn write_position<Q: ?Sized>(&self, key: &Q) -> Result<usize, usize>
+    where
+        C: Comparator<Q>,
+        K: Borrow<Q>,
+    {
+        let comparator = self.map.comparator();
+        self.writes
+            .binary_search_by(|(k, _)| comparator.compare(k.borrow(), key))
+    }
+
+    pub fn validate(self) -> Option<Vec<(K, Option<V>)>> {
+        if !self.conflicted && self.reads.iter().all(|cell_guard| cell_guard.is_current()) {
+            Some(self.writes)
+        } else {
+            None
+        }
+    }
+}
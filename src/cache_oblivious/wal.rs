@@ -0,0 +1,235 @@
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::btree_map::Record;
+use super::cell::Cell;
+use super::format::{self, checksum, invalid, Encode, FormatError};
+use super::packed_memory_array::PackedMemoryArray;
+
+// A durable map is a directory holding the last checkpoint, saved with the
+// native format, and a log of every write made since. The log starts with
+// magic "COBW" and a format version u16, followed by records of:
+//
+//   tag u8, payload length u32, the encoded key (and value for inserts),
+//   and an FNV-1a checksum u64 of everything before it in the record.
+//
+// A record is only applied once its checksum is intact, so a write torn by a
+// crash is dropped along with everything after it.
+const MAGIC: &[u8; 4] = b"COBW";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: u64 = 6;
+
+const INSERT_RECORD: u8 = 1;
+const REMOVE_RECORD: u8 = 2;
+// tag and payload length before, checksum after
+const RECORD_OVERHEAD: usize = 1 + 4 + 8;
+
+const CHECKPOINT_FILE: &str = "checkpoint";
+const CHECKPOINT_TEMP_FILE: &str = "checkpoint.tmp";
+const LOG_FILE: &str = "wal";
+
+// Records appended before the log is folded into a new checkpoint
+const CHECKPOINT_INTERVAL: usize = 1024;
+
+// The log, the checkpoint if there is one, and the records to replay over it
+type Recovered<K, V> = (
+    Wal<K, V>,
+    Option<PackedMemoryArray<Cell<K, V>>>,
+    Vec<Record<K, V>>,
+);
+
+type WriteCells<K, V> = fn(&PackedMemoryArray<Cell<K, V>>, BufWriter<File>) -> io::Result<()>;
+
+pub struct Wal<K, V> {
+    dir: PathBuf,
+    log: File,
+    // records in the log, none of which are in the checkpoint yet
+    pending: usize,
+    // set once a write fails, the log may then be missing records so it takes no more
+    failed: Option<io::ErrorKind>,
+    // the map itself doesn't require Encode, so the log keeps its own
+    encode_key: fn(&K, &mut Vec<u8>) -> io::Result<()>,
+    encode_value: fn(&V, &mut Vec<u8>) -> io::Result<()>,
+    write_cells: WriteCells<K, V>,
+}
+
+impl<K, V> Wal<K, V>
+where
+    K: Encode,
+    V: Encode,
+{
+    // Reads back the last checkpoint, if one was ever written, along with the
+    // intact records logged after it. Anything past the last intact record is
+    // cut off so new records follow straight on.
+    pub fn recover(dir: &Path) -> io::Result<Recovered<K, V>> {
+        fs::create_dir_all(dir)?;
+
+        let checkpoint = match File::open(dir.join(CHECKPOINT_FILE)) {
+            Ok(file) => Some(format::read_cells(BufReader::new(file))?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error),
+        };
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+
+        // a log cut short inside its header never held any records
+        if bytes.len() < HEADER_LEN as usize {
+            log.set_len(0)?;
+            log.write_all(MAGIC)?;
+            log.write_all(&FORMAT_VERSION.to_le_bytes())?;
+            log.sync_data()?;
+            bytes.clear();
+        } else {
+            if &bytes[0..4] != MAGIC {
+                return Err(invalid(FormatError::Magic));
+            }
+            if u16::from_le_bytes(bytes[4..6].try_into().unwrap()) != FORMAT_VERSION {
+                return Err(invalid(FormatError::Version));
+            }
+        }
+
+        let mut records = Vec::new();
+        let mut position = HEADER_LEN as usize;
+        while let Some((record, len)) = Self::read_record(&bytes[position.min(bytes.len())..])? {
+            records.push(record);
+            position += len;
+        }
+        if position < bytes.len() {
+            log.set_len(position as u64)?;
+            log.sync_data()?;
+        }
+
+        let wal = Wal {
+            dir: dir.to_path_buf(),
+            log,
+            pending: records.len(),
+            failed: None,
+            encode_key: K::encode::<Vec<u8>>,
+            encode_value: V::encode::<Vec<u8>>,
+            write_cells: format::write_cells::<K, V, BufWriter<File>>,
+        };
+        Ok((wal, checkpoint, records))
+    }
+
+    // The record at the start of `bytes` and its length, or None if it
+    // was never completely written
+    fn read_record(bytes: &[u8]) -> io::Result<Option<(Record<K, V>, usize)>> {
+        if bytes.len() < RECORD_OVERHEAD {
+            return Ok(None);
+        }
+        let payload_len = u32::from_le_bytes(bytes[1..5].try_into().unwrap()) as usize;
+        let len = match payload_len.checked_add(RECORD_OVERHEAD) {
+            Some(len) if len <= bytes.len() => len,
+            _ => return Ok(None),
+        };
+        let stored = u64::from_le_bytes(bytes[len - 8..len].try_into().unwrap());
+        if checksum(&bytes[..len - 8]) != stored {
+            return Ok(None);
+        }
+
+        // past the checksum, anything malformed was written that way
+        let mut payload = &bytes[5..len - 8];
+        let record = match bytes[0] {
+            INSERT_RECORD => Record::Insert(K::decode(&mut payload)?, V::decode(&mut payload)?),
+            REMOVE_RECORD => Record::Remove(K::decode(&mut payload)?),
+            _ => return Err(invalid(FormatError::Encoding)),
+        };
+        if !payload.is_empty() {
+            return Err(invalid(FormatError::Encoding));
+        }
+
+        Ok(Some((record, len)))
+    }
+}
+
+impl<K, V> Wal<K, V> {
+    pub fn needs_checkpoint(&self) -> bool {
+        self.pending >= CHECKPOINT_INTERVAL
+    }
+
+    // The record reaches the operating system in a single write before this
+    // returns, `sync` is what makes it survive a power loss
+    pub fn append(&mut self, record: Record<&K, &V>) -> io::Result<()> {
+        self.check()?;
+        let result = self.write_record(record);
+        self.fail_on(result)
+    }
+
+    fn write_record(&mut self, record: Record<&K, &V>) -> io::Result<()> {
+        let mut bytes = vec![0; 5];
+        match record {
+            Record::Insert(key, value) => {
+                bytes[0] = INSERT_RECORD;
+                (self.encode_key)(key, &mut bytes)?;
+                (self.encode_value)(value, &mut bytes)?;
+            }
+            Record::Remove(key) => {
+                bytes[0] = REMOVE_RECORD;
+                (self.encode_key)(key, &mut bytes)?;
+            }
+        }
+        let payload_len: u32 = (bytes.len() - 5)
+            .try_into()
+            .map_err(|_| invalid(FormatError::Overflow))?;
+        bytes[1..5].copy_from_slice(&payload_len.to_le_bytes());
+        let checksum = checksum(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        self.log.write_all(&bytes)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    // Saves `data` as the new checkpoint and empties the log. Replaying a log
+    // over a checkpoint that already holds its writes leaves every key as the
+    // last record set it, so a crash between the two steps is harmless.
+    pub fn checkpoint(&mut self, data: &PackedMemoryArray<Cell<K, V>>) -> io::Result<()> {
+        self.check()?;
+        let result = self.write_checkpoint(data);
+        self.fail_on(result)
+    }
+
+    fn write_checkpoint(&mut self, data: &PackedMemoryArray<Cell<K, V>>) -> io::Result<()> {
+        let temp_path = self.dir.join(CHECKPOINT_TEMP_FILE);
+        let file = File::create(&temp_path)?;
+        (self.write_cells)(data, BufWriter::new(file.try_clone()?))?;
+        file.sync_all()?;
+        fs::rename(&temp_path, self.dir.join(CHECKPOINT_FILE))?;
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+
+        self.log.set_len(HEADER_LEN)?;
+        self.log.sync_data()?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.check()?;
+        let result = self.log.sync_data();
+        self.fail_on(result)
+    }
+
+    // The failure that stopped the log, if one did
+    fn check(&self) -> io::Result<()> {
+        match self.failed {
+            Some(kind) => Err(kind.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn fail_on(&mut self, result: io::Result<()>) -> io::Result<()> {
+        if let Err(error) = &result {
+            self.failed = Some(error.kind());
+        }
+        result
+    }
+}
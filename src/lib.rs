@@ -1,18 +1,27 @@
-#![feature(new_uninit)]
-#![feature(maybe_uninit_slice)]
-#![feature(once_cell)]
-#![feature(ptr_as_uninit)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-#[cfg(not(target_env = "msvc"))]
-#[global_allocator]
-static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+extern crate alloc;
 
 mod cache_oblivious;
-pub use cache_oblivious::BTreeMap;
+#[cfg(feature = "mmap")]
+pub use cache_oblivious::Pod;
+pub use cache_oblivious::{
+    Allocator, BTreeMap, BTreeSet, BytesMap, Comparator, Error, Global, Monoid, OrdComparator,
+    SlabMap, Snapshot, Transaction,
+};
+#[cfg(feature = "std")]
+pub use cache_oblivious::{Encode, FormatError};
 
 #[cfg(test)]
 mod tests {
-    use crate::BTreeMap;
+    use crate::{BTreeMap, BTreeSet, BytesMap, Comparator, Error, Monoid, SlabMap};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cmp::Ordering;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::ptr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+    use std::sync::Arc;
     use std::thread;
     use std::time;
 
@@ -72,4 +81,1067 @@ mod tests {
 
         assert_eq!(tree.get(&99), Some(&100));
     }
+
+    #[test]
+    fn snapshot_ignores_later_writes() {
+        let mut tree = BTreeMap::<u8, String>::new(16);
+        tree.insert(3, String::from("Hello"));
+        tree.insert(8, String::from("World"));
+
+        let snapshot = tree.snapshot();
+        tree.insert(1, String::from("First"));
+        tree.insert(5, String::from("Middle"));
+        tree.insert(8, String::from("Changed"));
+
+        assert_eq!(snapshot.get(&1), None);
+        assert_eq!(snapshot.get(&3), Some(String::from("Hello")));
+        assert_eq!(snapshot.get(&8), Some(String::from("World")));
+        assert_eq!(
+            snapshot.iter().collect::<Vec<_>>(),
+            vec![(3, String::from("Hello")), (8, String::from("World"))]
+        );
+
+        // later keys get shifted into the cells the snapshot's index routes by
+        for seed in 0..20u64 {
+            let mut state = seed;
+            let mut next_key = || {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u32 % 1000
+            };
+            let mut tree = BTreeMap::<u32, u32>::new(64);
+            let keys = (0..40).map(|_| next_key()).collect::<Vec<_>>();
+            for &key in &keys {
+                tree.insert(key, key);
+            }
+            let snapshot = tree.snapshot();
+            for _ in 0..100 {
+                tree.insert(next_key(), 0);
+            }
+            for key in keys {
+                assert_eq!(snapshot.get(&key), Some(key));
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn snapshot_range_while_writing() {
+        let mut tree = BTreeMap::<u8, u8>::new(100);
+        for i in (0..100u8).step_by(2) {
+            tree.insert(i, i);
+        }
+
+        let snapshot = tree.snapshot();
+        let reader = thread::spawn(move || snapshot.range(10..=20).collect::<Vec<_>>());
+
+        for i in (1..100u8).step_by(2) {
+            tree.insert(i, i);
+        }
+
+        let expected = (10..=20u8).step_by(2).map(|i| (i, i)).collect::<Vec<_>>();
+        assert_eq!(reader.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn remove_values() {
+        let mut tree = BTreeMap::<u8, u8>::new(16);
+        for i in 1..10u8 {
+            tree.insert(i, i);
+        }
+
+        assert_eq!(tree.remove(&1), Some(1));
+        assert_eq!(tree.remove(&5), Some(5));
+        assert_eq!(tree.remove(&5), None);
+        tree.insert(5, 50);
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+
+        assert_eq!(tree.get(&1), None);
+        assert_eq!(tree.get(&2), Some(&2));
+        assert_eq!(tree.get(&5), Some(&50));
+    }
+
+    #[test]
+    fn transaction_moves_balance() {
+        // once capped, turns down anything bigger than it has handed out
+        struct Capped(Arc<(AtomicUsize, AtomicBool)>);
+
+        unsafe impl GlobalAlloc for Capped {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let (largest, capped) = &*self.0;
+                if !capped.load(AtomicOrdering::SeqCst) {
+                    largest.fetch_max(layout.size(), AtomicOrdering::SeqCst);
+                } else if layout.size() > largest.load(AtomicOrdering::SeqCst) {
+                    return ptr::null_mut();
+                }
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        let limit = Arc::new((AtomicUsize::new(0), AtomicBool::new(false)));
+        let mut accounts = BTreeMap::<u8, i32>::with_allocator(16, Capped(Arc::clone(&limit)));
+        accounts.insert(1, 100);
+        accounts.insert(2, 50);
+        accounts.insert(3, 0);
+
+        let before = accounts.snapshot();
+        let total = accounts.transaction(|tx| {
+            let from = tx.get(&1).unwrap();
+            let to = tx.get(&2).unwrap();
+            tx.insert(1, from - 30);
+            tx.insert(2, to + 30);
+            tx.remove(&3);
+            assert_eq!(tx.get(&2), Some(80));
+            assert_eq!(tx.get(&3), None);
+            from + to
+        });
+        assert_eq!(total.unwrap(), 150);
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+
+        assert_eq!(accounts.get(&1), Some(&70));
+        assert_eq!(accounts.get(&2), Some(&80));
+        assert_eq!(accounts.get(&3), None);
+        assert_eq!(
+            before.iter().collect::<Vec<_>>(),
+            vec![(1, 100), (2, 50), (3, 0)]
+        );
+
+        accounts.get_or_insert_atomic(9, 7).unwrap();
+
+        // out of room part way, the writes that did land are undone
+        limit.1.store(true, AtomicOrdering::SeqCst);
+        let result = accounts.transaction(|tx| {
+            tx.insert(1, 0);
+            tx.remove(&2);
+            for key in 10..200 {
+                tx.insert(key, 1);
+            }
+        });
+        assert_eq!(result, Err(Error::AllocationFailed));
+        assert_eq!(
+            accounts.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+            vec![(1, 70), (2, 80), (9, 7)]
+        );
+        assert_eq!(accounts.len(), 3);
+    }
+
+    #[test]
+    fn atomic_value_updates() {
+        let mut counters = BTreeMap::<u8, u32>::new(16);
+        counters.insert(1, 0);
+
+        for _ in 0..10 {
+            counters.fetch_update(&1, |count| Some(count + 1)).unwrap();
+        }
+        assert_eq!(
+            counters.fetch_update(&2, |count| Some(count + 1)),
+            Err(None)
+        );
+
+        assert_eq!(counters.compare_exchange(&1, &10, 20), Ok(10));
+        assert_eq!(counters.compare_exchange(&1, &10, 30), Err(Some(20)));
+
+        assert_eq!(counters.get_or_insert_atomic(1, 0), Ok(&20));
+        assert_eq!(counters.get_or_insert_atomic(2, 5), Ok(&5));
+        // room is made the way inserts make it
+        for i in 3..100 {
+            assert_eq!(counters.get_or_insert_atomic(i, 1), Ok(&1));
+        }
+        assert_eq!(counters.len(), 99);
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+
+        assert_eq!(counters.get(&1), Some(&20));
+        assert_eq!(counters.get(&2), Some(&5));
+    }
+
+    #[test]
+    fn iterate_in_order() {
+        let mut tree = BTreeMap::<u8, u8>::new(16);
+        for i in [5u8, 1, 9, 3, 7].iter() {
+            tree.insert(*i, i * 10);
+        }
+
+        let keys = tree.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(keys, vec![1, 3, 5, 7, 9]);
+        let range = tree.range(3..=7).collect::<Vec<_>>();
+        assert_eq!(range, vec![(&3, &30), (&5, &50), (&7, &70)]);
+        assert_eq!(tree.range(4..5).count(), 0);
+    }
+
+    #[test]
+    fn set_operations() {
+        let mut evens = BTreeSet::<u8>::new(16);
+        let mut threes = BTreeSet::<u8>::new(16);
+        for i in 0..10u8 {
+            evens.insert(i * 2);
+            threes.insert(i * 3);
+        }
+
+        assert!(!evens.insert(4));
+        assert!(evens.remove(&0));
+        assert!(!evens.remove(&1));
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+
+        assert!(evens.contains(&18));
+        assert!(!evens.contains(&0));
+        assert_eq!(
+            evens.intersection(&threes).collect::<Vec<_>>(),
+            vec![&6, &12, &18]
+        );
+        assert_eq!(
+            evens.difference(&threes).collect::<Vec<_>>(),
+            vec![&2, &4, &8, &10, &14, &16]
+        );
+        assert_eq!(evens.union(&threes).count(), 9 + 10 - 3);
+        assert_eq!(evens.range(5..9).collect::<Vec<_>>(), vec![&6, &8]);
+    }
+
+    #[test]
+    fn first_and_last_entries() {
+        let mut queue = BTreeMap::<u8, String>::new(16);
+        assert_eq!(queue.first_key_value(), None);
+        assert_eq!(queue.pop_last(), None);
+
+        for i in [5u8, 2, 8, 1, 9].iter() {
+            queue.insert(*i, i.to_string());
+        }
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+
+        assert_eq!(queue.first_key_value(), Some((&1, &String::from("1"))));
+        assert_eq!(queue.last_key_value(), Some((&9, &String::from("9"))));
+
+        assert_eq!(queue.pop_first(), Some((1, String::from("1"))));
+        assert_eq!(queue.pop_first(), Some((2, String::from("2"))));
+        assert_eq!(queue.pop_last(), Some((9, String::from("9"))));
+
+        let remaining = queue.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(remaining, vec![5, 8]);
+    }
+
+    #[test]
+    fn rank_and_select() {
+        let mut tree = BTreeMap::<u8, u8>::new(100);
+        for i in (0..200u8).step_by(2) {
+            tree.insert(i, i);
+        }
+
+        // straight after the writes, before the index has caught up
+        assert_eq!(tree.rank(&0), 0);
+        assert_eq!(tree.rank(&51), 26);
+        assert_eq!(tree.rank(&52), 26);
+        assert_eq!(tree.rank(&255), 100);
+
+        assert_eq!(tree.select(0), Some((&0, &0)));
+        assert_eq!(tree.select(95), Some((&190, &190)));
+        assert_eq!(tree.select(100), None);
+
+        for i in (0..20u8).step_by(2) {
+            tree.remove(&i);
+        }
+        tree.insert(1, 1);
+        assert_eq!(tree.rank(&40), 11);
+        assert_eq!(tree.select(0), Some((&1, &1)));
+        assert_eq!(tree.select(11), Some((&40, &40)));
+        assert_eq!(tree.first_key_value(), Some((&1, &1)));
+        assert_eq!(
+            tree.iter().map(|(k, _)| tree.rank(k)).collect::<Vec<_>>(),
+            (0..91).collect::<Vec<_>>()
+        );
+    }
+
+    struct Sum;
+
+    impl Monoid<u32, u32> for Sum {
+        type Summary = u32;
+
+        fn identity() -> u32 {
+            0
+        }
+
+        fn lift(_key: &u32, value: &u32) -> u32 {
+            *value
+        }
+
+        fn combine(lhs: &u32, rhs: &u32) -> u32 {
+            lhs + rhs
+        }
+    }
+
+    #[test]
+    fn aggregate_ranges() {
+        let mut tree = BTreeMap::<u32, u32, Sum>::with_monoid(100);
+        for i in 0..100 {
+            tree.insert(i, i * 2);
+        }
+
+        // straight after the writes, before the index has caught up
+        assert_eq!(tree.aggregate(..), (0..100).map(|i| i * 2).sum::<u32>());
+        assert_eq!(tree.aggregate(10..20), (10..20).map(|i| i * 2).sum::<u32>());
+        assert_eq!(tree.aggregate(3..=90), (3..=90).map(|i| i * 2).sum::<u32>());
+        assert_eq!(tree.aggregate(95..), (95..100).map(|i| i * 2).sum::<u32>());
+        assert_eq!(tree.aggregate(40..40), 0);
+
+        for i in 0..100 {
+            tree.insert(i + 100, 1);
+            if i % 3 == 0 {
+                tree.remove(&i);
+            }
+        }
+        let expected = (0..100).filter(|i| i % 3 != 0).map(|i| i * 2).sum::<u32>();
+        assert_eq!(tree.aggregate(..100), expected);
+        assert_eq!(tree.aggregate(..), expected + 100);
+
+        // values changed in place count too
+        *tree.get_mut(&50).unwrap() = 1000;
+        tree.iter_mut().for_each(|(_, value)| *value += 1);
+        assert_eq!(tree.aggregate(..100), expected + 900 + 66);
+
+        // and once the index has rebuilt under them
+        thread::sleep(time::Duration::from_millis(100));
+        tree.range_mut(100..).for_each(|(_, value)| *value = 0);
+        assert_eq!(tree.aggregate(100..), 0);
+        assert_eq!(tree.aggregate(..), expected + 900 + 66);
+
+        // scattered writes leave min keys stale, routing range starts early
+        let mut state = 0u64;
+        let mut next_key = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as u32 % 1000
+        };
+        let mut tree = BTreeMap::<u32, u32, Sum>::with_monoid(16);
+        for step in 0..400 {
+            let key = next_key();
+            if step % 4 == 3 {
+                tree.remove(&key);
+            } else {
+                tree.insert(key, key);
+            }
+            let start = next_key();
+            let sum = (start..start + 200)
+                .filter_map(|i| tree.get(&i).copied())
+                .sum::<u32>();
+            assert_eq!(tree.aggregate(start..start + 200), sum);
+        }
+    }
+
+    // ignores ASCII case, optionally in reverse
+    struct CaseInsensitive {
+        descending: bool,
+    }
+
+    impl Comparator<String> for CaseInsensitive {
+        fn compare(&self, lhs: &String, rhs: &String) -> Ordering {
+            let ordering = lhs
+                .bytes()
+                .map(|b| b.to_ascii_lowercase())
+                .cmp(rhs.bytes().map(|b| b.to_ascii_lowercase()));
+            if self.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        }
+    }
+
+    #[test]
+    fn custom_key_order() {
+        let comparator = CaseInsensitive { descending: true };
+        let mut tree = BTreeMap::<String, u32, (), _>::with_comparator(20, comparator);
+        for (i, word) in ["delta", "Alpha", "charlie", "Bravo", "echo"]
+            .iter()
+            .enumerate()
+        {
+            tree.insert(word.to_string(), i as u32);
+        }
+        tree.insert(String::from("ALPHA"), 10);
+
+        let keys: Vec<_> = tree.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["echo", "delta", "charlie", "Bravo", "ALPHA"]);
+        assert_eq!(tree.get(&String::from("alpha")), Some(&10));
+        assert_eq!(tree.get(&String::from("ECHO")), Some(&4));
+        assert_eq!(tree.get(&String::from("foxtrot")), None);
+
+        let middle: Vec<_> = tree
+            .range(String::from("D")..String::from("b"))
+            .map(|(_, v)| *v)
+            .collect();
+        assert_eq!(middle, vec![2, 3]);
+
+        tree.transaction(|transaction| {
+            transaction.insert(String::from("Foxtrot"), 5);
+            transaction.remove(&String::from("DELTA"));
+        })
+        .unwrap();
+        let keys: Vec<_> = tree.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["Foxtrot", "echo", "charlie", "Bravo", "ALPHA"]);
+    }
+
+    #[test]
+    fn front_coded_byte_keys() {
+        let mut map = BytesMap::<u32>::new(100);
+        // spread across many blocks, in no particular order
+        for i in (0..500u32).map(|i| i * 7919 % 500) {
+            let key = format!("tenant/{}/user/{:04}", i % 3, i);
+            assert_eq!(map.insert(key.as_bytes(), i), None);
+        }
+        assert_eq!(map.insert(b"tenant/1/user/0001", 1000), Some(1));
+        assert_eq!(map.insert(b"", 2000), None);
+        assert_eq!(map.len(), 501);
+
+        assert_eq!(map.get(b"tenant/1/user/0001"), Some(&1000));
+        assert_eq!(map.get(b"tenant/2/user/0497"), Some(&497));
+        assert_eq!(map.get(b""), Some(&2000));
+        assert_eq!(map.get(b"tenant/2/user/0500"), None);
+        assert_eq!(map.get(b"tenant/2/user/049"), None);
+
+        for i in (0..500u32).filter(|i| i % 5 != 0) {
+            let key = format!("tenant/{}/user/{:04}", i % 3, i);
+            assert_eq!(
+                map.remove(key.as_bytes()),
+                Some(if i == 1 { 1000 } else { i })
+            );
+        }
+        assert_eq!(map.remove(b"tenant/0/user/0003"), None);
+
+        let mut expected: Vec<_> = (0..500u32)
+            .filter(|i| i % 5 == 0)
+            .map(|i| format!("tenant/{}/user/{:04}", i % 3, i).into_bytes())
+            .chain(std::iter::once(Vec::new()))
+            .collect();
+        expected.sort();
+        let keys: Vec<_> = map.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(map.len(), 101);
+
+        // sized for a handful of keys, each one lands in front of its block
+        let mut map = BytesMap::<usize>::new(1);
+        for i in (0..2000).rev() {
+            let key = format!("a long shared prefix/{:05}", i);
+            assert_eq!(map.try_insert(key.as_bytes(), i), Ok(None));
+        }
+        for i in (0..2000).step_by(3) {
+            let key = format!("a long shared prefix/{:05}", i);
+            assert_eq!(map.remove(key.as_bytes()), Some(i));
+        }
+        assert_eq!(map.len(), 1333);
+        let values: Vec<_> = map.iter().map(|(_, &v)| v).collect();
+        assert_eq!(values, (0..2000).filter(|i| i % 3 != 0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn values_kept_out_of_line() {
+        // large enough that moving it with every rebalance would cost
+        #[derive(Debug, PartialEq)]
+        struct Payload([u64; 64]);
+
+        let mut map = SlabMap::<u32, Payload>::new(200);
+        for i in (0..200).rev() {
+            assert_eq!(map.insert(i, Payload([i as u64; 64])), None);
+        }
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+
+        assert_eq!(map.insert(8, Payload([0; 64])), Some(Payload([8; 64])));
+        for i in (0..200).step_by(2) {
+            assert_eq!(
+                map.remove(&i),
+                Some(Payload([if i == 8 { 0 } else { i as u64 }; 64]))
+            );
+        }
+        // freed slots are handed out again
+        map.insert(1000, Payload([1000; 64]));
+        thread::sleep(time::Duration::from_millis(50));
+        map.get_mut(&1).unwrap().0[0] = 42;
+
+        assert_eq!(map.len(), 101);
+        assert_eq!(map.get(&1).unwrap().0[..2], [42, 1]);
+        assert_eq!(map.get(&2), None);
+        let keys: Vec<_> = map.range(190..).map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![191, 193, 195, 197, 199, 1000]);
+        assert_eq!(map.iter().next_back().unwrap().1, &Payload([1000; 64]));
+    }
+
+    #[test]
+    fn entries_moved_not_cloned() {
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        struct Id(u32);
+
+        let mut tree = BTreeMap::<Id, Box<dyn Fn(u32) -> u32>>::new(200);
+        // descending inserts keep shifting cells along to make room
+        for i in (0..200).rev() {
+            tree.insert(Id(i), Box::new(move |x| x + i));
+        }
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+
+        for i in (0..200).step_by(3) {
+            assert_eq!(tree.remove(&Id(i)).map(|f| f(1)), Some(i + 1));
+        }
+        thread::sleep(time::Duration::from_millis(50));
+
+        assert_eq!(tree.get(&Id(0)).map(|f| f(1)), None);
+        assert_eq!(tree.get(&Id(100)).map(|f| f(1)), Some(101));
+        let keys: Vec<_> = tree.iter().map(|(k, f)| f(k.0)).take(4).collect();
+        assert_eq!(keys, vec![2, 4, 8, 10]);
+    }
+
+    #[test]
+    fn custom_allocator() {
+        // counts the bytes it has handed out and not had back
+        #[derive(Default)]
+        struct Counting(Arc<AtomicUsize>);
+
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                self.0.fetch_add(layout.size(), AtomicOrdering::SeqCst);
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                self.0.fetch_sub(layout.size(), AtomicOrdering::SeqCst);
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        let allocator = Counting::default();
+        let live = Arc::clone(&allocator.0);
+        let mut tree = BTreeMap::<u32, u32>::with_allocator(16, allocator);
+        let initial = live.load(AtomicOrdering::SeqCst);
+        assert!(initial > 0);
+
+        let mut other = BTreeMap::<u32, u32>::new(100);
+        for i in 0..100 {
+            other.insert(i, i);
+        }
+        // repacked into a bigger array from the same allocator
+        tree.append(&mut other);
+        assert!(live.load(AtomicOrdering::SeqCst) > initial);
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(tree.get(&99), Some(&99));
+
+        drop(tree);
+        assert_eq!(live.load(AtomicOrdering::SeqCst), 0);
+    }
+
+    #[test]
+    fn fallible_operations() {
+        let mut tree = BTreeMap::<u32, u32>::new(10);
+        for i in 0..10 {
+            assert_eq!(tree.try_insert(i, i), Ok(()));
+        }
+
+        // repacked with room for the rest, keeping what's there
+        assert_eq!(tree.try_reserve(90), Ok(()));
+        for i in 10..100 {
+            assert_eq!(tree.try_insert(i, i), Ok(()));
+        }
+        assert_eq!(tree.try_reserve(usize::MAX), Err(Error::CapacityExhausted));
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(tree.try_get(&5), Ok(Some(&5)));
+        assert_eq!(tree.try_get(&99), Ok(Some(&99)));
+        assert_eq!(tree.try_get(&100), Ok(None));
+    }
+
+    #[test]
+    fn fallible_allocation() {
+        // turns down anything bigger than a gigabyte
+        struct Limited;
+
+        unsafe impl GlobalAlloc for Limited {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                if layout.size() > 1 << 30 {
+                    ptr::null_mut()
+                } else {
+                    System.alloc(layout)
+                }
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        assert!(BTreeMap::<u32, u32>::try_with_capacity(100).is_ok());
+        // rounded up to the smallest array that holds a block
+        let mut empty = BTreeMap::<u32, u32>::try_with_capacity(0).unwrap();
+        assert!(empty.is_empty());
+        empty.insert(1, 1);
+        assert_eq!(empty.len(), 1);
+        assert_eq!(
+            BTreeMap::<u32, u32>::try_with_capacity(u32::MAX).err(),
+            Some(Error::CapacityExhausted)
+        );
+
+        let mut tree = BTreeMap::<u32, u32>::with_allocator(100, Limited);
+        for i in 0..100 {
+            tree.insert(i, i);
+        }
+        // an array for that many is tens of gigabytes
+        assert_eq!(tree.try_reserve(100_000_000), Err(Error::AllocationFailed));
+
+        // index update is delayed
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(tree.get(&42), Some(&42));
+        assert_eq!(tree.len(), 100);
+        tree.remove(&42);
+        tree.retain(|key, _| key % 2 == 0);
+        assert_eq!(tree.len(), tree.iter().count());
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[test]
+    fn synchronous_index() {
+        let mut tree = BTreeMap::<u32, u32>::new(100);
+        for i in (0..100).rev() {
+            tree.insert(i, i * 2);
+            // the index already covers the key just written
+            assert_eq!(tree.get(&i), Some(&(i * 2)));
+        }
+
+        for i in (0..100).step_by(2) {
+            assert_eq!(tree.remove(&i), Some(i * 2));
+            assert_eq!(tree.get(&i), None);
+        }
+        assert_eq!(tree.rank(&51), 25);
+        assert_eq!(tree.iter().map(|(k, _)| *k).max(), Some(99));
+    }
+
+    #[test]
+    fn mutate_values_in_place() {
+        let mut tree = BTreeMap::<u8, u8>::new(100);
+        for i in 0..20 {
+            tree.insert(i, i);
+        }
+
+        *tree.get_mut(&3).unwrap() = 30;
+        assert_eq!(tree.get(&3), Some(&30));
+        assert_eq!(tree.get_mut(&42), None);
+
+        for (_, value) in tree.range_mut(10..15) {
+            *value += 100;
+        }
+        for (key, value) in tree.iter_mut() {
+            if key % 2 == 0 {
+                *value += 1;
+            }
+        }
+
+        assert_eq!(tree.get(&2), Some(&3));
+        assert_eq!(tree.get(&3), Some(&30));
+        assert_eq!(tree.get(&12), Some(&113));
+        assert_eq!(tree.get(&13), Some(&113));
+        assert_eq!(tree.get(&15), Some(&15));
+    }
+
+    #[test]
+    fn retain_and_clear() {
+        let mut tree = BTreeMap::<u8, u8>::new(100);
+        for i in 0..60 {
+            tree.insert(i, i);
+        }
+
+        tree.retain(|key, _| key % 3 != 0);
+        let extracted = tree.extract_if(|key, _| *key >= 50).collect::<Vec<_>>();
+
+        assert_eq!(
+            extracted,
+            vec![
+                (50, 50),
+                (52, 52),
+                (53, 53),
+                (55, 55),
+                (56, 56),
+                (58, 58),
+                (59, 59)
+            ]
+        );
+        assert_eq!(tree.get(&3), None);
+        assert_eq!(tree.get(&4), Some(&4));
+        assert_eq!(tree.iter().count(), 33);
+        assert!(tree.iter().all(|(key, _)| key % 3 != 0 && *key < 50));
+
+        // the map keeps working after entries have been spread out again
+        tree.insert(3, 3);
+        assert_eq!(
+            tree.range(2..6).map(|(k, _)| *k).collect::<Vec<_>>(),
+            vec![2, 3, 4, 5]
+        );
+
+        tree.clear();
+        assert_eq!(tree.iter().next(), None);
+        assert_eq!(tree.get(&4), None);
+
+        // the array keeps the room it was created with
+        let mut tree = BTreeMap::<u32, u32>::new(32);
+        for i in 0..30 {
+            tree.insert(i, i);
+        }
+        tree.retain(|key, _| *key < 3);
+        for i in 3..100 {
+            tree.try_insert(i, i).unwrap();
+        }
+        assert_eq!(tree.len(), 100);
+    }
+
+    #[test]
+    fn split_off_and_append() {
+        let mut tree = BTreeMap::<u8, u8>::new(100);
+        for i in 0..40 {
+            tree.insert(i, i);
+        }
+
+        let mut upper = tree.split_off(&25);
+        assert_eq!(
+            tree.iter().map(|(k, _)| *k).collect::<Vec<_>>(),
+            (0..25).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            upper.iter().map(|(k, _)| *k).collect::<Vec<_>>(),
+            (25..40).collect::<Vec<_>>()
+        );
+        assert_eq!(tree.get(&25), None);
+        assert_eq!(upper.get(&30), Some(&30));
+
+        upper.insert(10, 100);
+        tree.append(&mut upper);
+        assert_eq!(upper.iter().next(), None);
+        assert_eq!(tree.iter().count(), 40);
+        assert_eq!(tree.get(&10), Some(&100));
+        assert_eq!(tree.get(&39), Some(&39));
+
+        // both maps keep accepting writes afterwards
+        tree.insert(50, 50);
+        upper.insert(1, 1);
+        assert_eq!(tree.last_key_value(), Some((&50, &50)));
+        assert_eq!(upper.get(&1), Some(&1));
+
+        // each half has the room the whole map had
+        let mut lower = BTreeMap::<u32, u32>::new(256);
+        for i in 0..100 {
+            lower.insert(i, i);
+        }
+        let mut upper = lower.split_off(&5);
+        for i in 5..40 {
+            lower.try_insert(i, i).unwrap();
+        }
+        for i in 100..300 {
+            upper.try_insert(i, i).unwrap();
+        }
+        assert_eq!(lower.len(), 40);
+        assert_eq!(upper.len(), 295);
+
+        lower.append(&mut upper);
+        for i in 300..400 {
+            lower.try_insert(i, i).unwrap();
+        }
+        assert_eq!(lower.len(), 400);
+    }
+
+    #[test]
+    fn standard_traits() {
+        let tree = BTreeMap::<u8, u8>::from([(3, 30), (1, 10), (2, 20), (1, 11)]);
+        assert_eq!(tree[&1], 11);
+        assert_eq!(tree.iter().count(), 3);
+
+        let mut copy = tree.clone();
+        assert!(copy == tree);
+        copy.insert(4, 40);
+        assert!(copy != tree);
+        assert!(copy > tree);
+        assert_eq!(tree.get(&4), None);
+
+        let mut extended = BTreeMap::<u8, u8>::default();
+        extended.extend(vec![(4, 40), (2, 20)]);
+        extended.extend((0..40).filter(|i| i % 2 == 1).map(|i| (i, i * 5)));
+        assert_eq!(extended.get(&1), Some(&5));
+        assert_eq!(extended.get(&4), Some(&40));
+        assert_eq!(extended.get(&39), Some(&195));
+
+        let collected = (1..=3).map(|i| (i, i * 10)).collect::<BTreeMap<u8, u8>>();
+        assert!(collected != tree);
+        let mut tree = tree;
+        tree.insert(1, 10);
+        assert!(collected == tree);
+
+        let hash = |tree: &BTreeMap<u8, u8>| {
+            let mut hasher = DefaultHasher::new();
+            tree.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&collected), hash(&tree));
+
+        // maps grow rather than run out of cells
+        let mut grown = BTreeMap::<u32, u32>::default();
+        for i in 0..1000 {
+            grown.insert(i, i);
+        }
+        let mut collected = (0..10).map(|i| (i, i)).collect::<BTreeMap<u32, u32>>();
+        for i in 0..100 {
+            collected.extend((0..5).map(|j| (1000 + i * 5 + j, 0)));
+        }
+        for i in (10..1000).rev() {
+            collected.insert(i, i);
+        }
+        assert_eq!(grown.len(), 1000);
+        assert_eq!(collected.len(), 1500);
+        assert!(collected.iter().map(|(k, _)| *k).eq(0..1500));
+    }
+
+    #[test]
+    fn consume_into_owned_entries() {
+        let mut tree = BTreeMap::<u8, String>::new(100);
+        for i in (0..30).rev() {
+            tree.insert(i, i.to_string());
+        }
+        let snapshot = tree.snapshot();
+
+        let entries = tree.into_iter().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 30);
+        assert_eq!(entries[0], (0, "0".to_string()));
+        assert_eq!(entries[29], (29, "29".to_string()));
+
+        // snapshots outlive the map they were taken from
+        assert_eq!(snapshot.get(&7), Some("7".to_string()));
+        assert_eq!(snapshot.iter().count(), 30);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let tree = (0..50u32)
+            .map(|i| (i * 3, format!("value {}", i)))
+            .collect::<BTreeMap<u32, String>>();
+
+        let json = serde_json::to_string(&tree).unwrap();
+        assert!(json.starts_with(r#"[[0,"value 0"],[3,"value 1"]"#));
+        let from_json: BTreeMap<u32, String> = serde_json::from_str(&json).unwrap();
+        assert!(from_json == tree);
+
+        let bytes = bincode::serialize(&tree).unwrap();
+        let from_bincode: BTreeMap<u32, String> = bincode::deserialize(&bytes).unwrap();
+        assert!(from_bincode == tree);
+        assert_eq!(from_bincode.get(&147), Some(&"value 49".to_string()));
+
+        // a length claiming far more entries than follow isn't reserved up front
+        let mut lying = (u64::MAX >> 3).to_le_bytes().to_vec();
+        lying.extend_from_slice(&[0; 8]);
+        assert!(bincode::deserialize::<BTreeMap<u32, String>>(&lying).is_err());
+
+        let mut unordered: BTreeMap<u32, u32> =
+            serde_json::from_str("[[5,1],[2,2],[5,3]]").unwrap();
+        assert_eq!(
+            unordered.iter().collect::<Vec<_>>(),
+            vec![(&2, &2), (&5, &3)]
+        );
+
+        // a deserialized map has room to keep going
+        for i in 6..500 {
+            unordered.try_insert(i, i).unwrap();
+        }
+        assert_eq!(unordered.len(), 496);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn save_and_load_layout() {
+        let mut tree = BTreeMap::<u32, String>::new(100);
+        for i in (0..80).rev() {
+            tree.insert(i, format!("value {}", i));
+        }
+        for i in (0..80).step_by(3) {
+            tree.remove(&i);
+        }
+
+        let mut saved = Vec::new();
+        tree.save_to(&mut saved).unwrap();
+        let loaded = BTreeMap::<u32, String>::load_from(saved.as_slice()).unwrap();
+
+        assert!(loaded == tree);
+        assert_eq!(loaded.get(&79), Some(&"value 79".to_string()));
+        assert_eq!(loaded.get(&78), None);
+
+        // cells come back where they were, so saving again gives the same bytes
+        let mut resaved = Vec::new();
+        loaded.save_to(&mut resaved).unwrap();
+        assert!(saved == resaved);
+
+        // still decodes, only the checksum gives it away
+        let mut corrupted = saved.clone();
+        let value = saved.windows(8).position(|w| w == b"value 40").unwrap();
+        corrupted[value + 6] ^= 1;
+        let error = BTreeMap::<u32, String>::load_from(corrupted.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn recover_prefix_after_crash() {
+        let dir = std::env::temp_dir().join(format!("cobt-durable-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // the contents after each committed operation
+        let mut model = std::collections::BTreeMap::new();
+        let mut states = vec![Vec::new()];
+        let mut tree = BTreeMap::<u32, String>::open(&dir).unwrap();
+        for i in 0..30u32 {
+            let key = (i * 7) % 20;
+            if i % 4 == 3 {
+                tree.remove(&key);
+                model.remove(&key);
+            } else {
+                tree.insert(key, format!("value {}", i));
+                model.insert(key, format!("value {}", i));
+            }
+            states.push(model.clone().into_iter().collect::<Vec<_>>());
+
+            if i == 9 {
+                tree.checkpoint().unwrap();
+                states.drain(..10);
+            }
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        let checkpoint = std::fs::read(dir.join("checkpoint")).unwrap();
+        let log = std::fs::read(dir.join("wal")).unwrap();
+        let crashed = dir.join("crashed");
+
+        let mut last_recovered = 0;
+        for offset in 0..=log.len() {
+            let _ = std::fs::remove_dir_all(&crashed);
+            std::fs::create_dir_all(&crashed).unwrap();
+            std::fs::write(crashed.join("checkpoint"), &checkpoint).unwrap();
+            std::fs::write(crashed.join("wal"), &log[..offset]).unwrap();
+
+            let recovered = BTreeMap::<u32, String>::open(&crashed).unwrap();
+            let entries = recovered
+                .iter()
+                .map(|(k, v)| (*k, v.clone()))
+                .collect::<Vec<_>>();
+            let prefix = states[last_recovered..]
+                .iter()
+                .position(|state| *state == entries)
+                .expect("recovered state isn't a prefix of committed operations");
+            last_recovered += prefix;
+        }
+        assert_eq!(last_recovered, states.len() - 1);
+
+        // recovery cuts off the torn record, so later writes replay too
+        let _ = std::fs::remove_dir_all(&crashed);
+        std::fs::create_dir_all(&crashed).unwrap();
+        std::fs::write(crashed.join("checkpoint"), &checkpoint).unwrap();
+        std::fs::write(crashed.join("wal"), &log[..log.len() - 3]).unwrap();
+        let mut recovered = BTreeMap::<u32, String>::open(&crashed).unwrap();
+        recovered.insert(100, String::from("after"));
+        drop(recovered);
+        let recovered = BTreeMap::<u32, String>::open(&crashed).unwrap();
+        assert_eq!(
+            recovered.iter().last(),
+            Some((&100, &String::from("after")))
+        );
+
+        // a fresh durable map grows past its starting capacity, checkpoints included
+        let grown = dir.join("grown");
+        let mut tree = BTreeMap::<u32, String>::open(&grown).unwrap();
+        for i in (0..2000u32).rev() {
+            tree.try_insert(i, format!("value {}", i)).unwrap();
+        }
+        assert_eq!(tree.try_remove(&7), Ok(Some(String::from("value 7"))));
+        drop(tree);
+        let recovered = BTreeMap::<u32, String>::open(&grown).unwrap();
+        assert_eq!(recovered.len(), 1999);
+        assert_eq!(recovered.get(&1999), Some(&String::from("value 1999")));
+
+        // pops and bulk moves are logged before they land
+        let mut recovered = BTreeMap::<u32, String>::open(&grown).unwrap();
+        assert_eq!(
+            recovered.try_pop_first(),
+            Ok(Some((0, String::from("value 0"))))
+        );
+        assert_eq!(recovered.try_pop_last().unwrap().unwrap().0, 1999);
+        let mut upper = recovered.try_split_off(&1000).unwrap();
+        upper.try_retain(|key, _| key % 2 == 0).unwrap();
+        recovered.try_append(&mut upper).unwrap();
+        drop(recovered);
+        let recovered = BTreeMap::<u32, String>::open(&grown).unwrap();
+        assert_eq!(recovered.len(), 1498);
+        assert_eq!(recovered.get(&1001), None);
+        assert_eq!(recovered.get(&1002), Some(&String::from("value 1002")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn file_backed_map() {
+        let path = std::env::temp_dir().join(format!("cobt-mapped-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut tree = BTreeMap::<u64, [u8; 4]>::mapped(&path, 100).unwrap();
+        for i in (0..80u64).rev() {
+            tree.insert(i, (i as u32).to_le_bytes());
+        }
+        tree.remove(&40);
+        tree.flush().unwrap();
+        drop(tree);
+
+        let mut tree = BTreeMap::<u64, [u8; 4]>::mapped(&path, 0).unwrap();
+        assert_eq!(tree.iter().count(), 79);
+        assert_eq!(tree.get(&79), Some(&79u32.to_le_bytes()));
+        assert_eq!(tree.get(&40), None);
+
+        // rebalancing happens in the file too
+        let upper = tree.split_off(&60);
+        tree.extend(upper);
+        tree.insert(40, [0; 4]);
+        drop(tree);
+
+        let tree = BTreeMap::<u64, [u8; 4]>::mapped(&path, 0).unwrap();
+        assert_eq!(tree.iter().count(), 80);
+        assert_eq!(tree.get(&40), Some(&[0; 4]));
+        drop(tree);
+
+        let error = BTreeMap::<u32, u32>::mapped(&path, 0).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // a presence byte that's neither 0 nor 1 is turned down, not trusted:
+        // the first cell's follows its u16 version, right after the header page
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[4096 + 2] = 7;
+        std::fs::write(&path, bytes).unwrap();
+        let error = BTreeMap::<u64, [u8; 4]>::mapped(&path, 0).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+
+        // a new file asking for no room gets the smallest array a map can have
+        let mut tree = BTreeMap::<u64, u64>::mapped(&path, 0).unwrap();
+        tree.insert(1, 10);
+        tree.insert(2, 20);
+        assert_eq!(tree.iter().count(), 2);
+        drop(tree);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
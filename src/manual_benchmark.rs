@@ -1,5 +1,9 @@
 use cache_oblivious_b_tree::BTreeMap;
 
+#[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
 fn main() {
     let mut tree = BTreeMap::new(16);
 